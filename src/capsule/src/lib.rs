@@ -1,14 +1,26 @@
 use candid::{CandidType, Principal};
 use ic_cdk::{api, storage};
-use ic_cdk::api::call::call;
+use ic_cdk::api::management_canister::main::raw_rand;
 use ic_cdk_macros::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 use log::{info};
-use ic_ledger_types::{AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs};
+use sha2::{Digest, Sha256};
+use ic_ledger_types::{AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs, DEFAULT_FEE, DEFAULT_SUBACCOUNT};
 
 const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+const SCHEMA_VERSION: u32 = 1; // 0 is the original layout, migrated via `LegacySkillTreeStorage`
+const ROYALTY_DIVISOR: u64 = 10; // 10% royalty
+const MAX_PAGE_SIZE: u64 = 100;
+const MAX_OWNERS_PER_QUERY: usize = 50;
+const MAX_HISTOGRAM_BUCKETS: u32 = 100;
+const FEATURED_COUNT: usize = 10;
+const FEATURED_REFRESH_INTERVAL: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day in nanoseconds
+const FEATURED_RETRY_DELAY: u64 = 60 * 60 * 1_000_000_000; // 1 hour in nanoseconds, after a failed refresh
+const MAX_RESERVATION_DURATION: u64 = 15 * 60 * 1_000_000_000; // 15 minutes in nanoseconds
+const RESERVATION_COOLDOWN: u64 = MAX_RESERVATION_DURATION; // before a holder may re-reserve the same NFT
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 struct SkillNFT {
@@ -22,6 +34,119 @@ struct SkillNFT {
     owner: Principal,
     resale_price: Option<u64>,
     is_active: bool,
+    max_per_buyer: Option<u32>, // Cap on purchases of this NFT by a single principal
+    royalties_earned: u64, // Total royalties credited to the creator from sales of this NFT
+    last_resale_price: Option<u64>, // Most recent resale price cleared by a sale, transfer or cancel
+    license: Option<String>, // Usage terms, e.g. "personal", "commercial" or a URL
+    listed_at: Option<u64>, // When the current resale listing started, in nanoseconds since epoch
+    resale_allowed: bool, // false keeps the NFT off the secondary market
+    acquired_at: Option<u64>, // When the current owner bought or received the NFT, in nanoseconds since epoch
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct Reservation {
+    holder: Principal,
+    expires_at: u64, // in nanoseconds since epoch
+}
+
+/// One page of NFTs plus the total number of matches across all pages.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct PagedNfts {
+    items: Vec<SkillNFT>,
+    total: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct RoyaltyCredit {
+    timestamp: u64, // in nanoseconds since epoch
+    amount: u64,
+}
+
+/// Criteria for `search_advanced`; every criterion that is set must match.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct SearchFilter {
+    creator: Option<Principal>,
+    min_price: Option<u64>,
+    max_price: Option<u64>,
+    license: Option<String>,
+    listed_only: bool, // only NFTs with a resale listing
+    offset: u64,
+    limit: u64,
+}
+
+/// The cached featured rotation, chosen from a `raw_rand` seed once per refresh interval.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, Default)]
+struct FeaturedSelection {
+    nft_ids: Vec<u64>,
+    seed: Vec<u8>,
+    refreshed_at: u64, // in nanoseconds since epoch
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct SupplyInfo {
+    minted: u64,
+    active: u64,
+    deactivated: u64,
+}
+
+/// A declining-price sale that moves linearly from `start_price` to `floor_price` over `duration`.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct DutchAuction {
+    seller: Principal,
+    start_price: u64,
+    floor_price: u64,
+    start_time: u64, // in nanoseconds since epoch
+    duration: u64,   // in nanoseconds
+}
+
+/// Something that happened to an NFT, recorded in its history.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+enum Event {
+    Minted,
+    Purchased { from: Principal, to: Principal, price: u64 },
+    Transferred { from: Principal, to: Principal },
+    ResaleListed { price: u64 },
+    ResaleCancelled,
+    LicenseChanged { license: Option<String> },
+    DutchAuctionStarted { start_price: u64, floor_price: u64, duration: u64 },
+    DutchAuctionCancelled,
+    Deactivated,
+    Reactivated,
+    CreatorRightsTransferred { from: Principal, to: Principal },
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct NftEvent {
+    timestamp: u64, // in nanoseconds since epoch
+    event: Event,
+}
+
+/// Anti-abuse rule: purchases above `price_threshold` need an account at least `min_age` nanoseconds old.
+#[derive(Clone, Copy, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+struct AccountAgeRule {
+    price_threshold: u64,
+    min_age: u64,
+}
+
+/// An NFT's current tradeable state, as reported by `availability`.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+enum Availability {
+    Available, // active, unlocked, unlisted and freshly minted
+    Listed { price: u64 },
+    InAuction,
+    Locked, // reserved by a buyer until the reservation expires
+    NotForSale, // bought or transferred since minting and not listed
+    Inactive,
+}
+
+/// How the 10% royalty is rounded when the price is not a multiple of ten.
+/// Defaults to `Floor`, so creators are never credited more than 10%; the remainder stays with the seller.
+#[derive(Clone, Copy, Debug, CandidType, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum RoyaltyRounding {
+    #[default]
+    Floor,
+    Round,
+    Ceil,
 }
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize, Default)]
@@ -30,10 +155,151 @@ struct SkillTreeStorage {
     next_id: u64,
     balances: HashMap<Principal, u64>,
     creator_royalties: HashMap<Principal, u64>,
+    purchase_counts: HashMap<u64, HashMap<Principal, u32>>, // nft_id -> buyer -> purchases
+    creator_sales: HashMap<Principal, HashMap<Principal, u64>>, // creator -> buyer -> purchases the creator sold
+    royalty_rounding: RoyaltyRounding,
+    operator_approvals: HashMap<Principal, HashSet<Principal>>, // owner -> approved operators
+    max_nfts_per_principal: Option<u64>, // None means unlimited
+    reservations: HashMap<u64, Reservation>,
+    initialized_at: Option<u64>, // set once in init, in nanoseconds since epoch; unknown for migrated baseline state
+    auto_withdraw_thresholds: HashMap<Principal, u64>,
+    dutch_auctions: HashMap<u64, DutchAuction>,
+    royalty_credits: HashMap<Principal, Vec<RoyaltyCredit>>, // creator -> credits in time order
+    featured: FeaturedSelection,
+    completed_resales: u64,
+    total_time_listed: u64, // summed listing durations of completed resales, in nanoseconds
+    buyers: HashSet<Principal>, // every principal that has completed a purchase
+    last_deposit_balance: HashMap<Principal, u64>, // deposit account balance claimed by sweeps in flight
+    history: HashMap<u64, Vec<NftEvent>>, // nft_id -> events in time order
+    admins: Vec<Principal>, // seeded with the deploying principal; never empty
+    admin_takedowns: HashSet<u64>, // NFTs deactivated by an admin, which only an admin may reactivate
+    first_seen: HashMap<Principal, u64>, // when each principal first deposited, minted or bought
+    account_age_rule: Option<AccountAgeRule>, // None disables the rule
+}
+
+/// `SkillNFT` as saved by schema version 0, before any of the later fields existed.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct LegacySkillNFT {
+    id: u64,
+    title: String,
+    description: String,
+    creator: Principal,
+    price: u64,
+    unlock_duration: Option<u64>,
+    metadata: HashMap<String, String>,
+    owner: Principal,
+    resale_price: Option<u64>,
+    is_active: bool,
+}
+
+/// `SkillTreeStorage` as saved by schema version 0. Candid rejects records that lack a
+/// non-`opt` field, so state in this shape is decoded separately and migrated in `post_upgrade`.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize, Default)]
+struct LegacySkillTreeStorage {
+    nfts: HashMap<u64, LegacySkillNFT>,
+    next_id: u64,
+    balances: HashMap<Principal, u64>,
+    creator_royalties: HashMap<Principal, u64>,
+}
+
+impl From<LegacySkillNFT> for SkillNFT {
+    fn from(nft: LegacySkillNFT) -> Self {
+        SkillNFT {
+            id: nft.id,
+            title: nft.title,
+            description: nft.description,
+            creator: nft.creator,
+            price: nft.price,
+            unlock_duration: nft.unlock_duration,
+            metadata: nft.metadata,
+            owner: nft.owner,
+            resale_price: nft.resale_price,
+            is_active: nft.is_active,
+            max_per_buyer: None,
+            royalties_earned: 0,
+            last_resale_price: None,
+            license: None,
+            listed_at: None,
+            resale_allowed: true,
+            acquired_at: None,
+        }
+    }
+}
+
+impl From<LegacySkillTreeStorage> for SkillTreeStorage {
+    fn from(legacy: LegacySkillTreeStorage) -> Self {
+        SkillTreeStorage {
+            nfts: legacy.nfts.into_iter().map(|(id, nft)| (id, nft.into())).collect(),
+            next_id: legacy.next_id,
+            balances: legacy.balances,
+            creator_royalties: legacy.creator_royalties,
+            ..Default::default()
+        }
+    }
+}
+
+/// The canister's full current configuration, both admin-set and built in.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct Settings {
+    ledger_canister_id: Principal,
+    ledger_fee: u64,
+    royalty_bps: u64,
+    royalty_rounding: RoyaltyRounding,
+    max_nfts_per_principal: Option<u64>,
+    max_page_size: u64,
+    max_reservation_duration: u64,
+    reservation_cooldown: u64,
+    featured_count: u64,
+    featured_refresh_interval: u64,
+    account_age_rule: Option<AccountAgeRule>,
+}
+
+/// Every cost the canister applies to a transaction.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct FeeSchedule {
+    royalty_bps: u64,
+    royalty_rounding: RoyaltyRounding,
+    deposit_fee: u64, // ledger fee deducted from each deposit when it is swept, in e8s
+    withdrawal_fee: u64, // ledger fee deducted from each withdrawal, in e8s
+    max_nfts_per_principal: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+struct CanisterInfo {
+    initialized_at: Option<u64>, // None if the canister was deployed before this was recorded
+    schema_version: u32,
+    total_mints: u64,
+    ledger_canister_id: Principal,
 }
 
 thread_local! {
     static STATE: RefCell<SkillTreeStorage> = RefCell::new(SkillTreeStorage::default());
+    static FEATURED_REFRESHING: Cell<bool> = const { Cell::new(false) };
+    static FEATURED_RETRY_PENDING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[init]
+fn init() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.initialized_at = Some(api::time());
+        state.admins = vec![api::caller()];
+    });
+    schedule_featured_refresh();
+}
+
+/// Arm the daily featured refresh timer, refreshing right away if a refresh is already due.
+/// Timers do not survive upgrades, so this runs from both `init` and `post_upgrade`.
+fn schedule_featured_refresh() {
+    ic_cdk_timers::set_timer_interval(Duration::from_nanos(FEATURED_REFRESH_INTERVAL), || {
+        ic_cdk::spawn(refresh_featured_nfts())
+    });
+    let due = STATE.with(|state| {
+        api::time() >= state.borrow().featured.refreshed_at.saturating_add(FEATURED_REFRESH_INTERVAL)
+    });
+    if due {
+        ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(refresh_featured_nfts()));
+    }
 }
 
 #[pre_upgrade]
@@ -46,10 +312,26 @@ fn pre_upgrade() {
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (saved_state,): (SkillTreeStorage,) = storage::stable_restore().expect("Failed to restore state");
+    let mut saved_state = match storage::stable_restore::<(SkillTreeStorage,)>() {
+        Ok((saved_state,)) => saved_state,
+        Err(_) => {
+            let (legacy,): (LegacySkillTreeStorage,) = storage::stable_restore().expect("Failed to restore state");
+            info!("Migrated stable state from schema version 0 to {}", SCHEMA_VERSION);
+            legacy.into()
+        }
+    };
+    seed_admins(&mut saved_state, api::caller());
     STATE.with(|state| {
         *state.borrow_mut() = saved_state;
     });
+    schedule_featured_refresh();
+}
+
+/// Give state saved before admins existed the upgrading principal as its first admin.
+fn seed_admins(state: &mut SkillTreeStorage, upgrader: Principal) {
+    if state.admins.is_empty() {
+        state.admins.push(upgrader);
+    }
 }
 
 /// Helper function to validate input fields.
@@ -66,6 +348,283 @@ fn validate_input(title: &str, description: &str, price: u64) -> Result<(), Stri
     Ok(())
 }
 
+/// Helper function to validate an optional license.
+fn validate_license(license: &Option<String>) -> Result<(), String> {
+    if license.as_ref().is_some_and(|license| license.trim().is_empty()) {
+        return Err("License cannot be empty".to_string());
+    }
+    Ok(())
+}
+
+/// Append an event that happened at `now` to an NFT's history.
+fn record_event(state: &mut SkillTreeStorage, nft_id: u64, event: Event, now: u64) {
+    state.history.entry(nft_id).or_default().push(NftEvent {
+        timestamp: now,
+        event,
+    });
+}
+
+/// Check whether a principal is an admin. Controllers always count as admins.
+fn is_admin(principal: &Principal) -> bool {
+    api::is_controller(principal) || STATE.with(|state| state.borrow().admins.contains(principal))
+}
+
+/// Reject callers that are neither admins nor controllers.
+fn ensure_admin() -> Result<(), String> {
+    if !is_admin(&api::caller()) {
+        return Err("Only an admin can perform this action".to_string());
+    }
+    Ok(())
+}
+
+/// Compute the creator royalty for a sale price using the given rounding mode.
+fn calculate_royalty(price: u64, rounding: RoyaltyRounding) -> u64 {
+    let royalty = price / ROYALTY_DIVISOR;
+    let remainder = price % ROYALTY_DIVISOR;
+    match rounding {
+        RoyaltyRounding::Floor => royalty,
+        RoyaltyRounding::Round if remainder * 2 >= ROYALTY_DIVISOR => royalty + 1,
+        RoyaltyRounding::Round => royalty,
+        RoyaltyRounding::Ceil if remainder > 0 => royalty + 1,
+        RoyaltyRounding::Ceil => royalty,
+    }
+}
+
+/// Ensure `principal` can receive one more NFT without exceeding the holding cap.
+fn check_holding_cap(state: &SkillTreeStorage, principal: Principal) -> Result<(), String> {
+    if let Some(limit) = state.max_nfts_per_principal {
+        let owned = state.nfts.values().filter(|nft| nft.owner == principal).count() as u64;
+        if owned >= limit {
+            return Err(format!("Recipient already owns the maximum of {} NFTs", limit));
+        }
+    }
+    Ok(())
+}
+
+/// Remove an NFT's resale listing, remembering its price for `relist_nft`.
+fn clear_listing(nft: &mut SkillNFT) {
+    if let Some(price) = nft.resale_price.take() {
+        nft.last_resale_price = Some(price);
+    }
+    nft.listed_at = None;
+}
+
+/// Reject secondary sales of an NFT whose creator has disabled resale.
+/// Sales by the creator themselves are primary sales and always allowed.
+fn check_resale_allowed(nft: &SkillNFT) -> Result<(), String> {
+    if !nft.resale_allowed && nft.owner != nft.creator {
+        return Err("The creator has disabled resale of this NFT".to_string());
+    }
+    Ok(())
+}
+
+/// Enforce the creator's per-buyer purchase limit for an NFT.
+fn check_purchase_limit(state: &SkillTreeStorage, nft: &SkillNFT, buyer: Principal) -> Result<(), String> {
+    if let Some(limit) = nft.max_per_buyer {
+        let purchases = state
+            .purchase_counts
+            .get(&nft.id)
+            .and_then(|counts| counts.get(&buyer))
+            .copied()
+            .unwrap_or(0);
+        if purchases >= limit {
+            return Err(format!("Purchase limit of {} reached for this NFT", limit));
+        }
+    }
+    Ok(())
+}
+
+/// Record the first time a principal completes a deposit, mint or purchase.
+fn record_first_seen(state: &mut SkillTreeStorage, principal: Principal, now: u64) {
+    state.first_seen.entry(principal).or_insert(now);
+}
+
+/// Enforce the minimum account age for purchases above the configured price threshold.
+fn check_account_age(state: &SkillTreeStorage, buyer: Principal, price: u64, now: u64) -> Result<(), String> {
+    if let Some(rule) = state.account_age_rule {
+        if price > rule.price_threshold {
+            let first_seen = state.first_seen.get(&buyer).copied().unwrap_or(now);
+            if now.saturating_sub(first_seen) < rule.min_age {
+                return Err(format!(
+                    "Purchases above {} require an account at least {} nanoseconds old",
+                    rule.price_threshold, rule.min_age
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Respect another buyer's unexpired reservation, on either sale path.
+fn check_not_reserved_by_other(state: &SkillTreeStorage, nft_id: u64, buyer: Principal, now: u64) -> Result<(), String> {
+    let reserved_by_other = state
+        .reservations
+        .get(&nft_id)
+        .is_some_and(|r| r.holder != buyer && r.expires_at > now);
+    if reserved_by_other {
+        return Err("NFT is reserved by another buyer".to_string());
+    }
+    Ok(())
+}
+
+/// Check that `buyer` may buy `nft` through `purchase_skill_nft` at `now`, ignoring their balance.
+fn check_purchasable(state: &SkillTreeStorage, nft: &SkillNFT, buyer: Principal, now: u64) -> Result<(), String> {
+    check_sale_terms(state, nft, buyer, now)?;
+    check_holding_cap(state, buyer)
+}
+
+/// Like `check_purchasable`, but without the buyer's holding cap, which scans every NFT.
+fn check_sale_terms(state: &SkillTreeStorage, nft: &SkillNFT, buyer: Principal, now: u64) -> Result<(), String> {
+    if !nft.is_active {
+        return Err("NFT is not active".to_string());
+    }
+    // Checked against the current owner, so this covers resale listings too
+    if buyer == nft.owner {
+        return Err("Cannot purchase your own NFT".to_string());
+    }
+    if state.dutch_auctions.contains_key(&nft.id) {
+        return Err("NFT is in a Dutch auction; use buy_dutch".to_string());
+    }
+    // Only freshly minted NFTs sell at the mint price
+    if nft.resale_price.is_none() && !is_freshly_minted(nft) {
+        return Err("NFT is not listed for resale".to_string());
+    }
+    check_not_reserved_by_other(state, nft.id, buyer, now)?;
+    check_purchase_limit(state, nft, buyer)?;
+    check_account_age(state, buyer, effective_price(nft), now)
+}
+
+/// Move an NFT to `buyer` for `price` at `now`, paying `seller` the price minus the creator's royalty.
+/// Callers must have checked the buyer's balance. Nothing is changed if a credit would overflow.
+fn settle_sale(
+    state: &mut SkillTreeStorage,
+    nft_id: u64,
+    buyer: Principal,
+    seller: Principal,
+    price: u64,
+    now: u64,
+) -> Result<(), String> {
+    let royalty = calculate_royalty(price, state.royalty_rounding);
+    let creator = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?.creator;
+
+    // Reject sales whose credits would not fit before touching any state
+    let seller_balance = *state.balances.get(&seller).unwrap_or(&0);
+    let seller_balance = seller_balance
+        .checked_add(price - royalty)
+        .ok_or("Sale proceeds would overflow the seller's balance".to_string())?;
+    let creator_royalty = *state.creator_royalties.get(&creator).unwrap_or(&0);
+    let creator_royalty = creator_royalty
+        .checked_add(royalty)
+        .ok_or("Royalty would overflow the creator's royalty balance".to_string())?;
+
+    // Update balances
+    *state.balances.entry(buyer).or_insert(0) -= price;
+    state.balances.insert(seller, seller_balance);
+
+    // Update NFT ownership
+    let nft = state.nfts.get_mut(&nft_id).unwrap();
+    let time_listed = nft.listed_at.map(|listed_at| now.saturating_sub(listed_at));
+    nft.owner = buyer;
+    nft.acquired_at = Some(now);
+    clear_listing(nft);
+    nft.royalties_earned = nft.royalties_earned.saturating_add(royalty);
+    state.reservations.remove(&nft_id);
+
+    // Record how long a resale listing took to sell
+    if let Some(time_listed) = time_listed {
+        state.completed_resales += 1;
+        state.total_time_listed = state.total_time_listed.saturating_add(time_listed);
+    }
+
+    state.buyers.insert(buyer);
+    record_first_seen(state, buyer, now);
+
+    // Record the purchase against the buyer's limit
+    *state
+        .purchase_counts
+        .entry(nft_id)
+        .or_default()
+        .entry(buyer)
+        .or_insert(0) += 1;
+
+    // Record sales made by the creator themselves, under whoever was the creator at the time
+    if seller == creator {
+        *state.creator_sales.entry(creator).or_default().entry(buyer).or_insert(0) += 1;
+    }
+
+    // Update royalties
+    state.creator_royalties.insert(creator, creator_royalty);
+    state.royalty_credits.entry(creator).or_default().push(RoyaltyCredit {
+        timestamp: now,
+        amount: royalty,
+    });
+
+    record_event(state, nft_id, Event::Purchased { from: seller, to: buyer, price }, now);
+    Ok(())
+}
+
+/// Compute a Dutch auction's price at `now`, never dropping below the floor.
+fn current_dutch_price(auction: &DutchAuction, now: u64) -> u64 {
+    let elapsed = now.saturating_sub(auction.start_time);
+    if elapsed >= auction.duration {
+        return auction.floor_price;
+    }
+    let drop = (auction.start_price - auction.floor_price) as u128 * elapsed as u128
+        / auction.duration as u128;
+    auction.start_price - drop as u64
+}
+
+/// The price a buyer would currently pay: the resale price if listed, otherwise the mint price.
+fn effective_price(nft: &SkillNFT) -> u64 {
+    nft.resale_price.unwrap_or(nft.price)
+}
+
+/// Whether `nft` has never left its creator. A creator who buys or receives their NFT back holds it
+/// like any other owner, so `acquired_at` tells the two apart.
+fn is_freshly_minted(nft: &SkillNFT) -> bool {
+    nft.owner == nft.creator && nft.acquired_at.is_none()
+}
+
+/// Whether `nft` can currently be bought through `purchase_skill_nft` by someone other than its owner:
+/// it is active, and either listed for resale or freshly minted.
+fn is_for_sale(nft: &SkillNFT) -> bool {
+    nft.is_active && (nft.resale_price.is_some() || is_freshly_minted(nft))
+}
+
+/// Whether `user` can use the skill behind `nft` at `now`.
+/// The creator always can; the owner can until `unlock_duration` has elapsed since they acquired it.
+fn access_valid(nft: &SkillNFT, user: Principal, now: u64) -> bool {
+    if user == nft.creator {
+        return true;
+    }
+    if user != nft.owner {
+        return false;
+    }
+    match (nft.unlock_duration, nft.acquired_at) {
+        (Some(duration), Some(acquired_at)) => now < acquired_at.saturating_add(duration),
+        _ => true,
+    }
+}
+
+/// Sort NFTs by ID and return the page at `offset`, with at most `MAX_PAGE_SIZE` items.
+/// Sorting keeps pages stable across calls regardless of `HashMap` iteration order.
+fn page_by_id(mut nfts: Vec<SkillNFT>, offset: u64, limit: u64) -> Vec<SkillNFT> {
+    nfts.sort_by_key(|nft| nft.id);
+    nfts.into_iter()
+        .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .collect()
+}
+
+/// Like `page_by_id`, but also reports the total number of NFTs.
+fn paged_nfts(nfts: Vec<SkillNFT>, offset: u64, limit: u64) -> PagedNfts {
+    let total = nfts.len() as u64;
+    PagedNfts {
+        items: page_by_id(nfts, offset, limit),
+        total,
+    }
+}
+
 /// Generate a unique ID for new NFTs.
 fn generate_unique_id() -> u64 {
     STATE.with(|state| {
@@ -78,14 +637,22 @@ fn generate_unique_id() -> u64 {
 
 /// Mint a new SkillNFT.
 #[update]
+#[allow(clippy::too_many_arguments)] // Candid arguments are positional
 fn mint_skill_nft(
     title: String,
     description: String,
     price: u64,
     unlock_duration: Option<u64>,
     metadata: HashMap<String, String>,
+    max_per_buyer: Option<u32>,
+    license: Option<String>,
+    resale_allowed: Option<bool>,
 ) -> Result<u64, String> {
     validate_input(&title, &description, price)?;
+    if max_per_buyer == Some(0) {
+        return Err("Purchase limit must be greater than zero".to_string());
+    }
+    validate_license(&license)?;
 
     let creator = api::caller();
     let id = generate_unique_id();
@@ -101,34 +668,41 @@ fn mint_skill_nft(
         owner: creator,
         resale_price: None,
         is_active: true,
+        max_per_buyer,
+        royalties_earned: 0,
+        last_resale_price: None,
+        license,
+        listed_at: None,
+        resale_allowed: resale_allowed.unwrap_or(true),
+        acquired_at: None,
     };
 
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        state.nfts.insert(id, nft);
-        info!("SkillNFT minted with ID: {}", id);
-        Ok(id)
-    })
+    STATE.with(|state| store_minted_nft(&mut state.borrow_mut(), nft, api::time()));
+    info!("SkillNFT minted with ID: {}", id);
+    Ok(id)
+}
+
+/// Store a newly minted NFT and start its history.
+fn store_minted_nft(state: &mut SkillTreeStorage, nft: SkillNFT, now: u64) {
+    let (id, creator) = (nft.id, nft.creator);
+    state.nfts.insert(id, nft);
+    record_event(state, id, Event::Minted, now);
+    record_first_seen(state, creator, now);
 }
 
 /// Purchase a SkillNFT.
 #[update]
-fn purchase_skill_nft(nft_id: u64) -> Result<(), String> {
+async fn purchase_skill_nft(nft_id: u64) -> Result<(), String> {
     let buyer = api::caller();
-    
+
     // First get NFT details
     let nft_details = STATE.with(|state| {
         let state = state.borrow();
         state.nfts.get(&nft_id).cloned()
     }).ok_or_else(|| "NFT not found".to_string())?;
 
-    // Validate NFT status
-    if !nft_details.is_active {
-        return Err("NFT is not active".to_string());
-    }
-    if buyer == nft_details.owner {
-        return Err("Cannot purchase your own NFT".to_string());
-    }
+    // Validate NFT status and the buyer's eligibility
+    STATE.with(|state| check_purchasable(&state.borrow(), &nft_details, buyer, api::time()))?;
 
     // Check buyer's balance
     let buyer_balance = STATE.with(|state| {
@@ -136,167 +710,1376 @@ fn purchase_skill_nft(nft_id: u64) -> Result<(), String> {
         *state.balances.get(&buyer).unwrap_or(&0)
     });
 
-    if buyer_balance < nft_details.price {
+    let price = effective_price(&nft_details);
+    if buyer_balance < price {
         return Err("Insufficient balance".to_string());
     }
 
     // Perform the purchase
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        // Update balances
-        state.balances.insert(buyer, buyer_balance - nft_details.price);
-        
-        let creator_balance = *state.balances.get(&nft_details.creator).unwrap_or(&0);
-        state.balances.insert(nft_details.creator, creator_balance + nft_details.price);
-
-        // Update NFT ownership
-        let mut nft = nft_details.clone();
-        nft.owner = buyer;
-        nft.resale_price = None;
-        state.nfts.insert(nft_id, nft);
-
-        // Update royalties
-        let royalty = nft_details.price / 10; // 10% royalty
-        let creator_royalty = *state.creator_royalties.get(&nft_details.creator).unwrap_or(&0);
-        state.creator_royalties.insert(nft_details.creator, creator_royalty + royalty);
-
-        info!("SkillNFT with ID: {} purchased by {:?}", nft_id, buyer);
-        Ok(())
-    })
+        settle_sale(&mut state, nft_id, buyer, nft_details.owner, price, api::time())?;
+        info!("SkillNFT with ID: {} purchased by {:?} for {}", nft_id, buyer, price);
+        Ok::<(), String>(())
+    })?;
+
+    // The purchase is already committed; a failed payout just leaves royalties accrued
+    if let Err(err) = auto_withdraw_royalties(nft_details.creator).await {
+        info!("Auto-withdrawal for {:?} failed: {}", nft_details.creator, err);
+    }
+    Ok(())
 }
 
-/// Set a resale price for a purchased SkillNFT.
+/// Start a Dutch auction whose price falls linearly from `start_price` to `floor_price` over `duration` nanoseconds.
 #[update]
-fn set_resale_price(nft_id: u64, price: u64) -> Result<(), String> {
-    if price == 0 {
-        return Err("Resale price must be greater than zero".to_string());
+fn start_dutch_auction(nft_id: u64, start_price: u64, floor_price: u64, duration: u64) -> Result<(), String> {
+    if floor_price == 0 {
+        return Err("Floor price must be greater than zero".to_string());
+    }
+    if start_price <= floor_price {
+        return Err("Start price must be greater than the floor price".to_string());
+    }
+    if duration == 0 {
+        return Err("Duration must be greater than zero".to_string());
     }
 
-    let owner = api::caller();
+    let seller = api::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if let Some(nft) = state.nfts.get_mut(&nft_id) {
-            if nft.owner != owner {
-                return Err("Only the owner can set the resale price".to_string());
-            }
-            nft.resale_price = Some(price);
-            info!("Resale price set for NFT ID: {}", nft_id);
-            Ok(())
-        } else {
-            Err("NFT not found".to_string())
+        let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
+        if nft.owner != seller {
+            return Err("Only the owner can start an auction".to_string());
+        }
+        check_resale_allowed(nft)?;
+        if !nft.is_active {
+            return Err("NFT is not active".to_string());
+        }
+        if nft.resale_price.is_some() {
+            return Err("Cancel the resale listing before starting an auction".to_string());
+        }
+        if state.dutch_auctions.contains_key(&nft_id) {
+            return Err("NFT is already in a Dutch auction".to_string());
         }
-    })
-}
-
-/// Retrieve NFT details.
-#[query]
-fn get_nft(nft_id: u64) -> Option<SkillNFT> {
-    STATE.with(|state| state.borrow().nfts.get(&nft_id).cloned())
-}
 
-/// Get all NFTs for a specific user.
-#[query]
-fn get_user_nfts(user: Principal) -> Vec<SkillNFT> {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .nfts
-            .values()
-            .filter(|nft| nft.owner == user)
-            .cloned()
-            .collect()
+        let now = api::time();
+        state.dutch_auctions.insert(
+            nft_id,
+            DutchAuction {
+                seller,
+                start_price,
+                floor_price,
+                start_time: now,
+                duration,
+            },
+        );
+        record_event(&mut state, nft_id, Event::DutchAuctionStarted { start_price, floor_price, duration }, now);
+        info!("Dutch auction started for NFT ID: {}", nft_id);
+        Ok(())
     })
 }
 
-/// Deactivate an NFT (e.g., if it violates policies).
+/// End a Dutch auction without a sale.
 #[update]
-fn deactivate_nft(nft_id: u64) -> Result<(), String> {
+fn cancel_dutch_auction(nft_id: u64) -> Result<(), String> {
     let caller = api::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if let Some(nft) = state.nfts.get_mut(&nft_id) {
-            if nft.creator != caller {
-                return Err("Only the creator can deactivate the NFT".to_string());
-            }
-            nft.is_active = false;
-            info!("NFT ID: {} has been deactivated", nft_id);
-            Ok(())
-        } else {
-            Err("NFT not found".to_string())
+        let auction = state.dutch_auctions.get(&nft_id).ok_or("No Dutch auction for this NFT".to_string())?;
+        if auction.seller != caller {
+            return Err("Only the seller can cancel the auction".to_string());
         }
+        state.dutch_auctions.remove(&nft_id);
+        record_event(&mut state, nft_id, Event::DutchAuctionCancelled, api::time());
+        info!("Dutch auction cancelled for NFT ID: {}", nft_id);
+        Ok(())
     })
 }
 
-/// Transfer ownership of a SkillNFT to another user.
+/// Buy an NFT from its Dutch auction at the current price.
 #[update]
-fn transfer_nft_ownership(nft_id: u64, new_owner: Principal) -> Result<(), String> {
-    let caller = api::caller();
+async fn buy_dutch(nft_id: u64) -> Result<(), String> {
+    let buyer = api::caller();
 
-    // Validate NFT and ownership
-    STATE.with(|state| {
+    let creator = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let nft = state.nfts.get_mut(&nft_id).ok_or("NFT not found".to_string())?;
-
-        if nft.owner != caller {
-            return Err("Only the current owner can transfer ownership".to_string());
-        }
+        let auction = state.dutch_auctions.get(&nft_id).ok_or("No Dutch auction for this NFT".to_string())?;
+        let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
         if !nft.is_active {
-            return Err("Cannot transfer an inactive NFT".to_string());
+            return Err("NFT is not active".to_string());
         }
-        if new_owner == caller {
-            return Err("New owner must be different from the current owner".to_string());
+        if buyer == nft.owner {
+            return Err("Cannot purchase your own NFT".to_string());
         }
 
-        // Update ownership
-        nft.owner = new_owner;
-        nft.resale_price = None; // Reset resale price upon transfer
-        info!(
-            "NFT ID: {} ownership transferred from {:?} to {:?}",
-            nft_id, caller, new_owner
-        );
-        Ok(())
-    })
-}
+        let seller = auction.seller;
+        let creator = nft.creator;
+        let now = api::time();
+        let price = current_dutch_price(auction, now);
+        if *state.balances.get(&buyer).unwrap_or(&0) < price {
+            return Err("Insufficient balance".to_string());
+        }
+        check_purchase_limit(&state, nft, buyer)?;
+        check_not_reserved_by_other(&state, nft_id, buyer, now)?;
+        check_account_age(&state, buyer, price, now)?;
+        check_holding_cap(&state, buyer)?;
 
+        settle_sale(&mut state, nft_id, buyer, seller, price, now)?;
+        state.dutch_auctions.remove(&nft_id);
+        info!("SkillNFT with ID: {} bought at Dutch auction by {:?} for {}", nft_id, buyer, price);
+        Ok(creator)
+    })?;
 
-/// Add balance to a user's account securely.
-#[update]
-async fn add_balance(amount: u64) -> Result<(), String> {
-    if amount == 0 {
-        return Err("Amount must be greater than zero".to_string());
+    // The purchase is already committed; a failed payout just leaves royalties accrued
+    if let Err(err) = auto_withdraw_royalties(creator).await {
+        info!("Auto-withdrawal for {:?} failed: {}", creator, err);
     }
+    Ok(())
+}
 
-    let caller = api::caller();
-    let canister_id = ic_cdk::id();
-    let tokens = Tokens::from_e8s(amount);
-    let transfer_args = TransferArgs {
-        memo: Memo(0),
-        amount: tokens,
-        fee: Tokens::from_e8s(10_000),
-        from_subaccount: None,
-        to: AccountIdentifier::new(&canister_id, &Subaccount([0; 32])),
-        created_at_time: None,
-    };
+/// Get the current price of an NFT's Dutch auction, or `None` if it has none.
+#[query]
+fn dutch_auction_price(nft_id: u64) -> Option<u64> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .dutch_auctions
+            .get(&nft_id)
+            .map(|auction| current_dutch_price(auction, api::time()))
+    })
+}
 
-    let transfer_result: Result<(u64,), _> = call(
+/// Pay out a creator's royalties if they have crossed their auto-withdraw threshold.
+async fn auto_withdraw_royalties(creator: Principal) -> Result<(), String> {
+    // Take the royalties before the ledger call so they cannot be paid out twice
+    let royalties = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let threshold = state.auto_withdraw_thresholds.get(&creator).copied();
+        let accrued = state.creator_royalties.get(&creator).copied().unwrap_or(0);
+        match threshold {
+            Some(threshold) if accrued >= threshold => {
+                state.creator_royalties.remove(&creator);
+                Some(accrued)
+            }
+            _ => None,
+        }
+    });
+    let Some(royalties) = royalties else {
+        return Ok(());
+    };
+
+    match transfer_to_principal(creator, royalties).await {
+        Ok(_block_index) => {
+            info!("Auto-withdrew {} royalties to {:?}", royalties, creator);
+            Ok(())
+        }
+        Err(err) => {
+            STATE.with(|state| restore_royalties(&mut state.borrow_mut(), creator, royalties));
+            Err(err)
+        }
+    }
+}
+
+/// Set the royalty balance at which the caller's royalties are paid out automatically.
+/// `None` turns auto-withdrawal off.
+#[update]
+fn set_auto_withdraw_threshold(threshold: Option<u64>) -> Result<(), String> {
+    if threshold.is_some_and(|t| t <= DEFAULT_FEE.e8s()) {
+        return Err("Threshold must be greater than the ledger fee".to_string());
+    }
+
+    let caller = api::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        match threshold {
+            Some(threshold) => state.auto_withdraw_thresholds.insert(caller, threshold),
+            None => state.auto_withdraw_thresholds.remove(&caller),
+        };
+        info!("Auto-withdraw threshold for {:?} set to {:?}", caller, threshold);
+        Ok(())
+    })
+}
+
+/// Reserve `nft_id` for `holder` until `now + duration`.
+///
+/// The holder must be able to buy the NFT and afford it, may hold only one live reservation,
+/// and must wait `RESERVATION_COOLDOWN` after their reservation expires before reserving the same NFT again.
+fn reserve(state: &mut SkillTreeStorage, nft_id: u64, holder: Principal, duration: u64, now: u64) -> Result<(), String> {
+    let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
+    if let Some(reservation) = state.reservations.get(&nft_id) {
+        if reservation.expires_at > now {
+            return Err("NFT is already reserved".to_string());
+        }
+        if reservation.holder == holder && now < reservation.expires_at.saturating_add(RESERVATION_COOLDOWN) {
+            return Err("Your reservation of this NFT expired too recently to reserve it again".to_string());
+        }
+    }
+    if state.reservations.values().any(|r| r.holder == holder && r.expires_at > now) {
+        return Err("You already hold a reservation".to_string());
+    }
+    check_purchasable(state, nft, holder, now)?;
+    if *state.balances.get(&holder).unwrap_or(&0) < effective_price(nft) {
+        return Err("Insufficient balance".to_string());
+    }
+
+    state.reservations.insert(
+        nft_id,
+        Reservation {
+            holder,
+            expires_at: now + duration,
+        },
+    );
+    Ok(())
+}
+
+/// Reserve an NFT for the caller so only they can purchase it for `duration` nanoseconds.
+#[update]
+fn reserve_nft(nft_id: u64, duration: u64) -> Result<(), String> {
+    if duration == 0 || duration > MAX_RESERVATION_DURATION {
+        return Err(format!(
+            "Reservation duration must be between 1 and {} nanoseconds",
+            MAX_RESERVATION_DURATION
+        ));
+    }
+
+    let caller = api::caller();
+    STATE.with(|state| {
+        reserve(&mut state.borrow_mut(), nft_id, caller, duration, api::time())?;
+        info!("NFT ID: {} reserved by {:?}", nft_id, caller);
+        Ok(())
+    })
+}
+
+/// Set a resale price for a purchased SkillNFT.
+#[update]
+fn set_resale_price(nft_id: u64, price: u64) -> Result<(), String> {
+    if price == 0 {
+        return Err("Resale price must be greater than zero".to_string());
+    }
+
+    let owner = api::caller();
+    STATE.with(|state| list_for_resale(&mut state.borrow_mut(), nft_id, owner, price, api::time()))
+}
+
+/// List `owner`'s NFT for resale at `price`, keeping the original listing time when only the price changes.
+fn list_for_resale(state: &mut SkillTreeStorage, nft_id: u64, owner: Principal, price: u64, now: u64) -> Result<(), String> {
+    if state.dutch_auctions.contains_key(&nft_id) {
+        return Err("Cannot list an NFT that is in a Dutch auction".to_string());
+    }
+    if let Some(nft) = state.nfts.get_mut(&nft_id) {
+        if nft.owner != owner {
+            return Err("Only the owner can set the resale price".to_string());
+        }
+        check_resale_allowed(nft)?;
+        nft.resale_price = Some(price);
+        nft.listed_at.get_or_insert(now);
+        record_event(state, nft_id, Event::ResaleListed { price }, now);
+        info!("Resale price set for NFT ID: {}", nft_id);
+        Ok(())
+    } else {
+        Err("NFT not found".to_string())
+    }
+}
+
+/// Remove the resale listing for an owned SkillNFT.
+#[update]
+fn cancel_resale(nft_id: u64) -> Result<(), String> {
+    let owner = api::caller();
+    STATE.with(|state| cancel_listing(&mut state.borrow_mut(), nft_id, owner, api::time()))
+}
+
+/// Remove `owner`'s resale listing of an NFT.
+fn cancel_listing(state: &mut SkillTreeStorage, nft_id: u64, owner: Principal, now: u64) -> Result<(), String> {
+    let nft = state.nfts.get_mut(&nft_id).ok_or("NFT not found".to_string())?;
+    if nft.owner != owner {
+        return Err("Only the owner can cancel the resale listing".to_string());
+    }
+    if nft.resale_price.is_none() {
+        return Err("NFT is not listed for resale".to_string());
+    }
+    clear_listing(nft);
+    record_event(state, nft_id, Event::ResaleCancelled, now);
+    info!("Resale listing cancelled for NFT ID: {}", nft_id);
+    Ok(())
+}
+
+/// Relist an owned SkillNFT at its most recent resale price.
+#[update]
+fn relist_nft(nft_id: u64) -> Result<(), String> {
+    let owner = api::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.dutch_auctions.contains_key(&nft_id) {
+            return Err("Cannot list an NFT that is in a Dutch auction".to_string());
+        }
+        let nft = state.nfts.get_mut(&nft_id).ok_or("NFT not found".to_string())?;
+        if nft.owner != owner {
+            return Err("Only the owner can relist the NFT".to_string());
+        }
+        check_resale_allowed(nft)?;
+        if nft.resale_price.is_some() {
+            return Err("NFT is already listed for resale".to_string());
+        }
+        let price = nft.last_resale_price.ok_or("NFT has no previous resale price".to_string())?;
+        nft.resale_price = Some(price);
+        nft.listed_at = Some(api::time());
+        record_event(&mut state, nft_id, Event::ResaleListed { price }, api::time());
+        info!("NFT ID: {} relisted at {}", nft_id, price);
+        Ok(())
+    })
+}
+
+/// Set how royalties are rounded (admins only).
+#[update]
+fn set_royalty_rounding(rounding: RoyaltyRounding) -> Result<(), String> {
+    ensure_admin()?;
+    STATE.with(|state| {
+        state.borrow_mut().royalty_rounding = rounding;
+        info!("Royalty rounding set to {:?}", rounding);
+        Ok(())
+    })
+}
+
+/// Get the current royalty rounding mode.
+#[query]
+fn get_royalty_rounding() -> RoyaltyRounding {
+    STATE.with(|state| state.borrow().royalty_rounding)
+}
+
+/// Set the maximum number of NFTs a single principal may own (admins only).
+/// `None` removes the limit.
+#[update]
+fn set_max_nfts_per_principal(limit: Option<u64>) -> Result<(), String> {
+    ensure_admin()?;
+    if limit == Some(0) {
+        return Err("Limit must be greater than zero".to_string());
+    }
+    STATE.with(|state| {
+        state.borrow_mut().max_nfts_per_principal = limit;
+        info!("Max NFTs per principal set to {:?}", limit);
+        Ok(())
+    })
+}
+
+/// Require accounts to reach a minimum age before buying above a price threshold (admins only).
+/// `None` disables the rule.
+#[update]
+fn set_account_age_rule(rule: Option<AccountAgeRule>) -> Result<(), String> {
+    ensure_admin()?;
+    STATE.with(|state| {
+        state.borrow_mut().account_age_rule = rule;
+        info!("Account age rule set to {:?}", rule);
+        Ok(())
+    })
+}
+
+/// Retrieve NFT details.
+#[query]
+fn get_nft(nft_id: u64) -> Option<SkillNFT> {
+    STATE.with(|state| state.borrow().nfts.get(&nft_id).cloned())
+}
+
+/// Get every recorded event for an NFT, oldest first.
+#[query]
+fn get_nft_history(nft_id: u64) -> Vec<NftEvent> {
+    STATE.with(|state| state.borrow().history.get(&nft_id).cloned().unwrap_or_default())
+}
+
+/// Get the royalty recipient and amount for a hypothetical sale, like EIP-2981's `royaltyInfo`.
+#[query]
+fn royalty_info(nft_id: u64, sale_price: u64) -> Result<(Principal, u64), String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
+        Ok((nft.creator, calculate_royalty(sale_price, state.royalty_rounding)))
+    })
+}
+
+/// Set or clear the license terms of an NFT (creator only).
+#[update]
+fn set_license(nft_id: u64, license: Option<String>) -> Result<(), String> {
+    validate_license(&license)?;
+
+    let caller = api::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let nft = state.nfts.get_mut(&nft_id).ok_or("NFT not found".to_string())?;
+        if nft.creator != caller {
+            return Err("Only the creator can set the license".to_string());
+        }
+        nft.license = license.clone();
+        record_event(&mut state, nft_id, Event::LicenseChanged { license }, api::time());
+        info!("License updated for NFT ID: {}", nft_id);
+        Ok(())
+    })
+}
+
+/// Get all active NFTs with the given license.
+#[query]
+fn get_nfts_by_license(license: String) -> Vec<SkillNFT> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .nfts
+            .values()
+            .filter(|nft| nft.is_active && nft.license.as_deref() == Some(license.as_str()))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Search active NFTs by creator, effective price range, license and listing status, ordered by ID.
+/// A price bound only matches NFTs that are for sale.
+#[query]
+fn search_advanced(filter: SearchFilter) -> Vec<SkillNFT> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let nfts: Vec<SkillNFT> = state
+            .nfts
+            .values()
+            .filter(|nft| nft.is_active)
+            .filter(|nft| filter.creator.is_none_or(|creator| nft.creator == creator))
+            .filter(|nft| filter.min_price.is_none_or(|min| is_for_sale(nft) && effective_price(nft) >= min))
+            .filter(|nft| filter.max_price.is_none_or(|max| is_for_sale(nft) && effective_price(nft) <= max))
+            .filter(|nft| filter.license.is_none() || nft.license == filter.license)
+            .filter(|nft| !filter.listed_only || nft.resale_price.is_some())
+            .cloned()
+            .collect();
+        page_by_id(nfts, filter.offset, filter.limit)
+    })
+}
+
+/// Summarize whether an NFT can currently be traded.
+#[query]
+fn availability(nft_id: u64) -> Result<Availability, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
+        Ok(availability_at(&state, nft, api::time()))
+    })
+}
+
+/// Classify `nft`'s tradeable state at `now`.
+fn availability_at(state: &SkillTreeStorage, nft: &SkillNFT, now: u64) -> Availability {
+    if !nft.is_active {
+        Availability::Inactive
+    } else if state.dutch_auctions.contains_key(&nft.id) {
+        Availability::InAuction
+    } else if state.reservations.get(&nft.id).is_some_and(|r| r.expires_at > now) {
+        Availability::Locked
+    } else if let Some(price) = nft.resale_price {
+        Availability::Listed { price }
+    } else if !is_for_sale(nft) {
+        Availability::NotForSale
+    } else {
+        Availability::Available
+    }
+}
+
+/// Get active NFTs the caller is eligible to buy and can afford with their balance, ordered by ID.
+#[query]
+fn affordable_nfts(offset: u64, limit: u64) -> Vec<SkillNFT> {
+    let caller = api::caller();
+    let now = api::time();
+    STATE.with(|state| {
+        let state = state.borrow();
+        // The holding cap depends only on the caller, so check it once rather than per NFT
+        if check_holding_cap(&state, caller).is_err() {
+            return Vec::new();
+        }
+        let balance = *state.balances.get(&caller).unwrap_or(&0);
+        let nfts: Vec<SkillNFT> = state
+            .nfts
+            .values()
+            .filter(|nft| effective_price(nft) <= balance)
+            .filter(|nft| check_sale_terms(&state, nft, caller, now).is_ok())
+            .cloned()
+            .collect();
+        page_by_id(nfts, offset, limit)
+    })
+}
+
+/// Get one page of a user's NFTs, ordered by ID, `limit` (at most 100) at a time.
+#[query]
+fn get_user_nfts_paged(user: Principal, offset: u64, limit: u64) -> PagedNfts {
+    STATE.with(|state| {
+        let nfts: Vec<SkillNFT> = state
+            .borrow()
+            .nfts
+            .values()
+            .filter(|nft| nft.owner == user)
+            .cloned()
+            .collect();
+        paged_nfts(nfts, offset, limit)
+    })
+}
+
+/// Check whether a user currently has access to an NFT's skill.
+/// Expired NFTs stay owned but no longer grant access.
+#[query]
+fn has_access(nft_id: u64, user: Principal) -> bool {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .nfts
+            .get(&nft_id)
+            .is_some_and(|nft| access_valid(nft, user, api::time()))
+    })
+}
+
+/// Get all NFTs for a specific user, optionally leaving out those whose access has expired.
+#[query]
+fn get_user_nfts(user: Principal, exclude_expired: Option<bool>) -> Vec<SkillNFT> {
+    let exclude_expired = exclude_expired.unwrap_or(false);
+    let now = api::time();
+    STATE.with(|state| {
+        state
+            .borrow()
+            .nfts
+            .values()
+            .filter(|nft| nft.owner == user)
+            .filter(|nft| !exclude_expired || access_valid(nft, user, now))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Get active NFTs owned by any of up to 50 principals, ordered by ID, `limit` (at most 100) at a time.
+#[query]
+fn get_nfts_owned_by_any(owners: Vec<Principal>, offset: u64, limit: u64) -> Result<Vec<SkillNFT>, String> {
+    if owners.len() > MAX_OWNERS_PER_QUERY {
+        return Err(format!("At most {} owners can be queried at once", MAX_OWNERS_PER_QUERY));
+    }
+
+    let owners: HashSet<Principal> = owners.into_iter().collect();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let nfts: Vec<SkillNFT> = state
+            .nfts
+            .values()
+            .filter(|nft| nft.is_active && owners.contains(&nft.owner))
+            .cloned()
+            .collect();
+        Ok(page_by_id(nfts, offset, limit))
+    })
+}
+
+/// Check whether the caller has not yet used a title (case-insensitive).
+/// This is advisory only; titles are not globally unique.
+#[query]
+fn is_title_available(title: String) -> bool {
+    let caller = api::caller();
+    let title = title.trim().to_lowercase();
+    STATE.with(|state| {
+        !state
+            .borrow()
+            .nfts
+            .values()
+            .any(|nft| nft.creator == caller && nft.title.trim().to_lowercase() == title)
+    })
+}
+
+/// Get the royalties credited to the caller in `[start_ns, end_ns)`.
+#[query]
+fn royalty_income_between(start_ns: u64, end_ns: u64) -> u64 {
+    let caller = api::caller();
+    STATE.with(|state| {
+        state
+            .borrow()
+            .royalty_credits
+            .get(&caller)
+            .map(|credits| {
+                credits
+                    .iter()
+                    .filter(|credit| credit.timestamp >= start_ns && credit.timestamp < end_ns)
+                    .map(|credit| credit.amount)
+                    .sum()
+            })
+            .unwrap_or(0)
+    })
+}
+
+/// Get the NFTs that have earned the most royalties, highest first, at most 100.
+#[query]
+fn get_top_royalty_earners(limit: u64) -> Vec<SkillNFT> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut nfts: Vec<SkillNFT> = state
+            .nfts
+            .values()
+            .filter(|nft| nft.royalties_earned > 0)
+            .cloned()
+            .collect();
+        nfts.sort_by(|a, b| b.royalties_earned.cmp(&a.royalties_earned).then(a.id.cmp(&b.id)));
+        nfts.truncate(limit.min(MAX_PAGE_SIZE) as usize);
+        nfts
+    })
+}
+
+/// Export every NFT the caller created, ordered by ID, `limit` (at most 100) at a time.
+#[query]
+fn export_my_nfts(offset: u64, limit: u64) -> Vec<SkillNFT> {
+    let caller = api::caller();
+    STATE.with(|state| {
+        let state = state.borrow();
+        let nfts: Vec<SkillNFT> = state
+            .nfts
+            .values()
+            .filter(|nft| nft.creator == caller)
+            .cloned()
+            .collect();
+        page_by_id(nfts, offset, limit)
+    })
+}
+
+/// Get every principal who bought NFTs from the caller as their creator and how many purchases they made, most first.
+/// Resales between other owners are not counted.
+#[query]
+fn get_my_buyers() -> Vec<(Principal, u64)> {
+    let caller = api::caller();
+    STATE.with(|state| {
+        let mut buyers: Vec<(Principal, u64)> = state
+            .borrow()
+            .creator_sales
+            .get(&caller)
+            .map(|buyers| buyers.iter().map(|(buyer, count)| (*buyer, *count)).collect())
+            .unwrap_or_default();
+        buyers.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        buyers
+    })
+}
+
+/// Add an admin (admins only).
+#[update]
+fn add_admin(admin: Principal) -> Result<(), String> {
+    ensure_admin()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.admins.contains(&admin) {
+            return Err("Principal is already an admin".to_string());
+        }
+        state.admins.push(admin);
+        info!("Admin {:?} added by {:?}", admin, api::caller());
+        Ok(())
+    })
+}
+
+/// Remove an admin (admins only). The last admin cannot be removed.
+#[update]
+fn remove_admin(admin: Principal) -> Result<(), String> {
+    ensure_admin()?;
+    STATE.with(|state| remove_admin_from(&mut state.borrow_mut(), admin))?;
+    info!("Admin {:?} removed by {:?}", admin, api::caller());
+    Ok(())
+}
+
+/// Remove `admin` from the admin list, which must never become empty.
+fn remove_admin_from(state: &mut SkillTreeStorage, admin: Principal) -> Result<(), String> {
+    if !state.admins.contains(&admin) {
+        return Err("Principal is not an admin".to_string());
+    }
+    if state.admins.len() == 1 {
+        return Err("Cannot remove the last admin".to_string());
+    }
+    state.admins.retain(|a| *a != admin);
+    Ok(())
+}
+
+/// Get all admins.
+#[query]
+fn get_admins() -> Vec<Principal> {
+    STATE.with(|state| state.borrow().admins.clone())
+}
+
+/// Deactivate an NFT (e.g., if it violates policies). Allowed for the creator or an admin.
+#[update]
+fn deactivate_nft(nft_id: u64) -> Result<(), String> {
+    let caller = api::caller();
+    let caller_is_admin = is_admin(&caller);
+    STATE.with(|state| deactivate(&mut state.borrow_mut(), nft_id, caller, caller_is_admin, api::time()))
+}
+
+/// Deactivate an NFT on behalf of `caller`; a deactivation by an admin who is not the creator is a takedown.
+fn deactivate(state: &mut SkillTreeStorage, nft_id: u64, caller: Principal, caller_is_admin: bool, now: u64) -> Result<(), String> {
+    if let Some(nft) = state.nfts.get_mut(&nft_id) {
+        if nft.creator != caller && !caller_is_admin {
+            return Err("Only the creator or an admin can deactivate the NFT".to_string());
+        }
+        let takedown = nft.creator != caller;
+        nft.is_active = false;
+        if takedown {
+            state.admin_takedowns.insert(nft_id);
+        }
+        record_event(state, nft_id, Event::Deactivated, now);
+        info!("NFT ID: {} has been deactivated", nft_id);
+        Ok(())
+    } else {
+        Err("NFT not found".to_string())
+    }
+}
+
+/// Reactivate a deactivated NFT. Allowed for the creator or an admin.
+#[update]
+fn reactivate_nft(nft_id: u64) -> Result<(), String> {
+    let caller = api::caller();
+    let caller_is_admin = is_admin(&caller);
+    STATE.with(|state| reactivate(&mut state.borrow_mut(), nft_id, caller, caller_is_admin, api::time()))
+}
+
+/// Reactivate an NFT on behalf of `caller`; only an admin may lift an admin takedown.
+fn reactivate(state: &mut SkillTreeStorage, nft_id: u64, caller: Principal, caller_is_admin: bool, now: u64) -> Result<(), String> {
+    let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
+    if nft.creator != caller && !caller_is_admin {
+        return Err("Only the creator or an admin can reactivate the NFT".to_string());
+    }
+    if nft.is_active {
+        return Err("NFT is already active".to_string());
+    }
+    if state.admin_takedowns.contains(&nft_id) && !caller_is_admin {
+        return Err("Only an admin can reactivate an NFT taken down by an admin".to_string());
+    }
+
+    state.nfts.get_mut(&nft_id).unwrap().is_active = true;
+    state.admin_takedowns.remove(&nft_id);
+    record_event(state, nft_id, Event::Reactivated, now);
+    info!("NFT ID: {} has been reactivated", nft_id);
+    Ok(())
+}
+
+/// Transfer ownership of a SkillNFT to another user.
+#[update]
+fn transfer_nft_ownership(nft_id: u64, new_owner: Principal) -> Result<(), String> {
+    let caller = api::caller();
+    STATE.with(|state| transfer_nft(&mut state.borrow_mut(), nft_id, caller, new_owner, api::time()))
+}
+
+/// Move an NFT to `new_owner` on behalf of its owner or an approved operator.
+fn transfer_nft(state: &mut SkillTreeStorage, nft_id: u64, caller: Principal, new_owner: Principal, now: u64) -> Result<(), String> {
+    // Validate NFT and ownership
+    let nft = state.nfts.get(&nft_id).ok_or("NFT not found".to_string())?;
+    let owner = nft.owner;
+
+    let is_operator = state
+        .operator_approvals
+        .get(&owner)
+        .is_some_and(|operators| operators.contains(&caller));
+    if owner != caller && !is_operator {
+        return Err("Only the current owner or an approved operator can transfer ownership".to_string());
+    }
+    if !nft.is_active {
+        return Err("Cannot transfer an inactive NFT".to_string());
+    }
+    if new_owner == owner {
+        return Err("New owner must be different from the current owner".to_string());
+    }
+    if state.dutch_auctions.contains_key(&nft_id) {
+        return Err("Cannot transfer an NFT in a Dutch auction".to_string());
+    }
+    check_holding_cap(state, new_owner)?;
+
+    // Update ownership
+    let nft = state.nfts.get_mut(&nft_id).unwrap();
+    nft.owner = new_owner;
+    nft.acquired_at = Some(now);
+    clear_listing(nft); // Reset resale price upon transfer
+    state.reservations.remove(&nft_id);
+    record_event(state, nft_id, Event::Transferred { from: owner, to: new_owner }, now);
+    info!(
+        "NFT ID: {} ownership transferred from {:?} to {:?} by {:?}",
+        nft_id, owner, new_owner, caller
+    );
+    Ok(())
+}
+
+/// Hand an NFT's creator rights, and its future royalties, to another principal.
+/// Royalties already accrued stay with the current creator.
+#[update]
+fn transfer_creator_rights(nft_id: u64, new_creator: Principal) -> Result<(), String> {
+    let caller = api::caller();
+    STATE.with(|state| transfer_creator(&mut state.borrow_mut(), nft_id, caller, new_creator, api::time()))
+}
+
+/// Move `caller`'s creator rights over an NFT to `new_creator`.
+///
+/// Rights cannot go to a secondary owner: as creator they would bypass the creator's resale settings.
+fn transfer_creator(state: &mut SkillTreeStorage, nft_id: u64, caller: Principal, new_creator: Principal, now: u64) -> Result<(), String> {
+    let nft = state.nfts.get_mut(&nft_id).ok_or("NFT not found".to_string())?;
+    if nft.creator != caller {
+        return Err("Only the creator can transfer creator rights".to_string());
+    }
+    if new_creator == caller {
+        return Err("New creator must be different from the current creator".to_string());
+    }
+    if new_creator == nft.owner {
+        return Err("Cannot transfer creator rights to the NFT's current owner".to_string());
+    }
+    nft.creator = new_creator;
+    record_event(state, nft_id, Event::CreatorRightsTransferred { from: caller, to: new_creator }, now);
+    info!(
+        "NFT ID: {} creator rights transferred from {:?} to {:?}",
+        nft_id, caller, new_creator
+    );
+    Ok(())
+}
+
+/// Approve an operator to transfer all of the caller's current and future NFTs.
+#[update]
+fn approve_all(operator: Principal) -> Result<(), String> {
+    let caller = api::caller();
+    if operator == caller {
+        return Err("Cannot approve yourself as an operator".to_string());
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.operator_approvals.entry(caller).or_default().insert(operator);
+        info!("{:?} approved operator {:?}", caller, operator);
+        Ok(())
+    })
+}
+
+/// Revoke an operator's approval over all of the caller's NFTs.
+#[update]
+fn revoke_all(operator: Principal) -> Result<(), String> {
+    let caller = api::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let operators = state
+            .operator_approvals
+            .get_mut(&caller)
+            .filter(|operators| operators.contains(&operator))
+            .ok_or("Operator is not approved".to_string())?;
+        operators.remove(&operator);
+        if operators.is_empty() {
+            state.operator_approvals.remove(&caller);
+        }
+        info!("{:?} revoked operator {:?}", caller, operator);
+        Ok(())
+    })
+}
+
+
+/// The ledger account a principal sends ICP to before calling `add_balance`.
+fn deposit_account(principal: Principal) -> AccountIdentifier {
+    AccountIdentifier::new(&ic_cdk::id(), &Subaccount::from(principal))
+}
+
+/// Claim the growth of `principal`'s deposit account for a sweep into the canister's main account.
+///
+/// Every deposit is swept, so the deposit account only holds unswept ICP; `last_deposit_balance`
+/// is the part of it already claimed by sweeps still in flight. Returns the amount to sweep.
+fn begin_deposit(state: &mut SkillTreeStorage, principal: Principal, observed: u64) -> Result<u64, String> {
+    let claimed = *state.last_deposit_balance.get(&principal).unwrap_or(&0);
+    // A lower or equal reading, e.g. from an older concurrent call, must never lower the baseline
+    if observed <= claimed {
+        return Err("No new deposit found".to_string());
+    }
+    let amount = observed - claimed;
+    if amount <= DEFAULT_FEE.e8s() {
+        return Err("Deposit does not cover the ledger fee".to_string());
+    }
+    state
+        .balances
+        .get(&principal)
+        .unwrap_or(&0)
+        .checked_add(amount - DEFAULT_FEE.e8s())
+        .ok_or("Deposit would overflow the balance".to_string())?;
+    state.last_deposit_balance.insert(principal, observed);
+    Ok(amount)
+}
+
+/// Release a claim made by `begin_deposit`, crediting what reached the main account if the sweep succeeded.
+/// Returns the amount credited.
+fn finish_deposit(state: &mut SkillTreeStorage, principal: Principal, amount: u64, swept: bool) -> u64 {
+    let claimed = state.last_deposit_balance.remove(&principal).unwrap_or(0).saturating_sub(amount);
+    if claimed > 0 {
+        state.last_deposit_balance.insert(principal, claimed);
+    }
+    if !swept {
+        return 0;
+    }
+    // The ledger fee for the sweep came out of the deposit
+    let credited = amount - DEFAULT_FEE.e8s();
+    let balance = state.balances.entry(principal).or_insert(0);
+    *balance = balance.saturating_add(credited);
+    credited
+}
+
+/// Get the caller's deposit account as hex.
+#[query]
+fn get_deposit_account() -> String {
+    deposit_account(api::caller()).to_hex()
+}
+
+/// Add balance to a user's account securely.
+/// Sweeps newly arrived ICP from the caller's deposit account into the canister's main account
+/// and credits what arrived there, i.e. the deposit minus the ledger fee.
+#[update]
+async fn add_balance() -> Result<u64, String> {
+    let caller = api::caller();
+    let observed = ic_ledger_types::account_balance(
+        Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
+        AccountBalanceArgs {
+            account: deposit_account(caller),
+        },
+    ).await
+    .map_err(|err| format!("Failed to add balance: {:?}", err))?
+    .e8s();
+
+    // The claim is made after the await, so concurrent calls cannot sweep the same deposit twice
+    let amount = STATE.with(|state| begin_deposit(&mut state.borrow_mut(), caller, observed))?;
+
+    // Move the deposit into the main account that every payout is sent from
+    let sweep = ledger_transfer(
+        Some(Subaccount::from(caller)),
+        AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT),
+        amount,
+    ).await;
+
+    let credited = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let credited = finish_deposit(&mut state, caller, amount, sweep.is_ok());
+        if sweep.is_ok() {
+            record_first_seen(&mut state, caller, api::time());
+        }
+        credited
+    });
+    sweep.map_err(|err| format!("Failed to add balance: {}", err))?;
+    info!("Added {} balance to {:?}", credited, caller);
+    Ok(credited)
+}
+
+/// Send `amount` e8s from the canister's account to `to`'s default ICP account.
+/// The ledger fee is deducted from `amount`.
+async fn transfer_to_principal(to: Principal, amount: u64) -> Result<u64, String> {
+    ledger_transfer(None, AccountIdentifier::new(&to, &DEFAULT_SUBACCOUNT), amount).await
+}
+
+/// Send `amount` e8s from one of the canister's subaccounts to `to`, deducting the ledger fee from `amount`.
+async fn ledger_transfer(from_subaccount: Option<Subaccount>, to: AccountIdentifier, amount: u64) -> Result<u64, String> {
+    if amount <= DEFAULT_FEE.e8s() {
+        return Err("Amount does not cover the ledger fee".to_string());
+    }
+
+    let transfer_args = TransferArgs {
+        memo: Memo(0),
+        amount: Tokens::from_e8s(amount - DEFAULT_FEE.e8s()),
+        fee: DEFAULT_FEE,
+        from_subaccount,
+        to,
+        created_at_time: None,
+    };
+
+    match ic_ledger_types::transfer(
         Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
-        "icrc1_transfer",
-        (transfer_args,),
-    ).await;
+        transfer_args,
+    ).await {
+        Ok(Ok(block_index)) => Ok(block_index),
+        Ok(Err(err)) => Err(format!("Ledger transfer failed: {:?}", err)),
+        Err(err) => Err(format!("Ledger call failed: {:?}", err)),
+    }
+}
+
+/// Debit `amount` from `principal`'s balance ahead of a withdrawal's ledger call.
+/// Amounts that cannot cover the ledger fee are rejected without touching the balance.
+fn begin_withdrawal(state: &mut SkillTreeStorage, principal: Principal, amount: u64) -> Result<(), String> {
+    if amount <= DEFAULT_FEE.e8s() {
+        return Err("Amount does not cover the ledger fee".to_string());
+    }
+    let balance = state.balances.entry(principal).or_insert(0);
+    if *balance < amount {
+        return Err("Insufficient balance".to_string());
+    }
+    *balance -= amount;
+    Ok(())
+}
+
+/// Credit back a withdrawal debited by `begin_withdrawal` whose transfer failed.
+fn restore_withdrawal(state: &mut SkillTreeStorage, principal: Principal, amount: u64) {
+    let balance = state.balances.entry(principal).or_insert(0);
+    *balance = balance.saturating_add(amount);
+}
+
+/// Take all of `principal`'s accrued royalties ahead of a withdrawal's ledger call.
+/// Royalties that cannot cover the ledger fee are left in place.
+fn begin_royalty_withdrawal(state: &mut SkillTreeStorage, principal: Principal) -> Result<u64, String> {
+    let royalties = state.creator_royalties.get(&principal).copied().unwrap_or(0);
+    if royalties == 0 {
+        return Err("No royalties to withdraw".to_string());
+    }
+    if royalties <= DEFAULT_FEE.e8s() {
+        return Err("Royalties do not cover the ledger fee".to_string());
+    }
+    state.creator_royalties.remove(&principal);
+    Ok(royalties)
+}
+
+/// Credit back royalties taken for a payout whose transfer failed.
+fn restore_royalties(state: &mut SkillTreeStorage, principal: Principal, amount: u64) {
+    let royalties = state.creator_royalties.entry(principal).or_insert(0);
+    *royalties = royalties.saturating_add(amount);
+}
+
+/// Withdraw `amount` e8s of the caller's balance to `to`'s default ICP account (ledger fee deducted).
+///
+/// The balance is debited before the ledger call and credited back if the transfer fails, so
+/// concurrent withdrawals can never spend the same funds twice.
+#[update]
+async fn withdraw_balance(amount: u64, to: Principal) -> Result<(), String> {
+    if amount == 0 {
+        return Err("Amount must be greater than zero".to_string());
+    }
+
+    let caller = api::caller();
+    STATE.with(|state| begin_withdrawal(&mut state.borrow_mut(), caller, amount))?;
+
+    match transfer_to_principal(to, amount).await {
+        Ok(_block_index) => {
+            info!("Withdrew {} balance from {:?} to {:?}", amount, caller, to);
+            Ok(())
+        }
+        Err(err) => {
+            STATE.with(|state| restore_withdrawal(&mut state.borrow_mut(), caller, amount));
+            Err(format!("Failed to withdraw balance: {}", err))
+        }
+    }
+}
 
-    match transfer_result {
-        Ok((_block_index,)) => {
+/// Withdraw all of the caller's accrued royalties to their default ICP account (ledger fee deducted).
+///
+/// Uses the same debit-first ordering as `withdraw_balance`.
+#[update]
+async fn withdraw_royalties() -> Result<u64, String> {
+    let caller = api::caller();
+    let royalties = STATE.with(|state| begin_royalty_withdrawal(&mut state.borrow_mut(), caller))?;
+
+    match transfer_to_principal(caller, royalties).await {
+        Ok(_block_index) => {
+            info!("Withdrew {} royalties to {:?}", royalties, caller);
+            Ok(royalties)
+        }
+        Err(err) => {
+            STATE.with(|state| restore_royalties(&mut state.borrow_mut(), caller, royalties));
+            Err(format!("Failed to withdraw royalties: {}", err))
+        }
+    }
+}
+
+/// Drop the per-principal settings and bookkeeping kept for a closed account.
+fn forget_account(state: &mut SkillTreeStorage, principal: Principal) {
+    state.operator_approvals.remove(&principal);
+    for operators in state.operator_approvals.values_mut() {
+        operators.remove(&principal);
+    }
+    state.operator_approvals.retain(|_, operators| !operators.is_empty());
+    state.auto_withdraw_thresholds.remove(&principal);
+    state.first_seen.remove(&principal);
+    state.reservations.retain(|_, reservation| reservation.holder != principal);
+}
+
+/// Reject closing `principal`'s account while anything they own can still be sold, since every
+/// sale would credit the closed account again.
+fn check_nothing_for_sale(state: &SkillTreeStorage, principal: Principal) -> Result<(), String> {
+    let selling = state.nfts.values().any(|nft| nft.owner == principal && is_for_sale(nft))
+        || state.dutch_auctions.values().any(|auction| auction.seller == principal);
+    if selling {
+        return Err(
+            "Remove your resale listings and auctions and deactivate your unsold NFTs before closing the account"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Close the caller's account, paying out their full balance and royalties.
+///
+/// A total too small to cover the ledger fee cannot be paid out and is forfeited.
+#[update]
+async fn close_account() -> Result<(), String> {
+    let caller = api::caller();
+
+    // Remove the caller's funds before the ledger call so nothing can be spent concurrently
+    let (balance, royalties, total) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        check_nothing_for_sale(&state, caller)?;
+        let balance = state.balances.get(&caller).copied().unwrap_or(0);
+        let royalties = state.creator_royalties.get(&caller).copied().unwrap_or(0);
+        let total = balance
+            .checked_add(royalties)
+            .ok_or("Balance and royalties overflow".to_string())?;
+        state.balances.remove(&caller);
+        state.creator_royalties.remove(&caller);
+        if total <= DEFAULT_FEE.e8s() {
+            forget_account(&mut state, caller);
+        }
+        Ok::<_, String>((balance, royalties, total))
+    })?;
+
+    if total <= DEFAULT_FEE.e8s() {
+        info!("Account closed for {:?}, forfeited {}", caller, total);
+        return Ok(());
+    }
+
+    match transfer_to_principal(caller, total).await {
+        Ok(_block_index) => {
+            STATE.with(|state| forget_account(&mut state.borrow_mut(), caller));
+            info!("Account closed for {:?}, paid out {}", caller, total);
+            Ok(())
+        }
+        Err(err) => {
+            // Restore the funds so the caller can retry
             STATE.with(|state| {
                 let mut state = state.borrow_mut();
-                let balance = state.balances.entry(caller).or_insert(0);
-                *balance += amount;
-                info!("Added {} balance to {:?}", amount, caller);
-                Ok(())
-            })
+                if balance > 0 {
+                    restore_withdrawal(&mut state, caller, balance);
+                }
+                if royalties > 0 {
+                    restore_royalties(&mut state, caller, royalties);
+                }
+            });
+            Err(format!("Failed to close account: {}", err))
+        }
+    }
+}
+
+/// Get every configurable parameter and built-in limit in one place.
+#[query]
+fn get_settings() -> Settings {
+    STATE.with(|state| {
+        let state = state.borrow();
+        Settings {
+            ledger_canister_id: Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
+            ledger_fee: DEFAULT_FEE.e8s(),
+            royalty_bps: 10_000 / ROYALTY_DIVISOR,
+            royalty_rounding: state.royalty_rounding,
+            max_nfts_per_principal: state.max_nfts_per_principal,
+            max_page_size: MAX_PAGE_SIZE,
+            max_reservation_duration: MAX_RESERVATION_DURATION,
+            reservation_cooldown: RESERVATION_COOLDOWN,
+            featured_count: FEATURED_COUNT as u64,
+            featured_refresh_interval: FEATURED_REFRESH_INTERVAL,
+            account_age_rule: state.account_age_rule,
+        }
+    })
+}
+
+/// Get the royalty and fees the canister applies, so users can see all costs before transacting.
+#[query]
+fn fee_schedule() -> FeeSchedule {
+    STATE.with(|state| {
+        let state = state.borrow();
+        FeeSchedule {
+            royalty_bps: 10_000 / ROYALTY_DIVISOR,
+            royalty_rounding: state.royalty_rounding,
+            deposit_fee: DEFAULT_FEE.e8s(),
+            withdrawal_fee: DEFAULT_FEE.e8s(),
+            max_nfts_per_principal: state.max_nfts_per_principal,
+        }
+    })
+}
+
+/// Get deployment details for the running canister.
+#[query]
+fn canister_info() -> CanisterInfo {
+    STATE.with(|state| {
+        let state = state.borrow();
+        CanisterInfo {
+            initialized_at: state.initialized_at,
+            schema_version: SCHEMA_VERSION,
+            total_mints: state.next_id,
+            ledger_canister_id: Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
+        }
+    })
+}
+
+/// Get one page of active NFTs, ordered by ID, `limit` (at most 100) at a time.
+#[query]
+fn get_active_nfts_paged(offset: u64, limit: u64) -> PagedNfts {
+    STATE.with(|state| {
+        let nfts: Vec<SkillNFT> = state
+            .borrow()
+            .nfts
+            .values()
+            .filter(|nft| nft.is_active)
+            .cloned()
+            .collect();
+        paged_nfts(nfts, offset, limit)
+    })
+}
+
+/// Whether `metadata` contains every `(key, value)` pair in `filters`.
+/// Keys match case-insensitively and values exactly, so an empty filter list matches anything.
+fn metadata_matches(metadata: &HashMap<String, String>, filters: &[(String, String)]) -> bool {
+    filters.iter().all(|(key, value)| {
+        metadata
+            .iter()
+            .any(|(k, v)| k.to_lowercase() == key.to_lowercase() && v == value)
+    })
+}
+
+/// Find active NFTs whose metadata contains every `(key, value)` pair, paged by ID.
+/// Keys match case-insensitively and values exactly; no filters matches every active NFT.
+#[query]
+fn find_nfts_by_metadata(filters: Vec<(String, String)>, offset: u64, limit: u64) -> PagedNfts {
+    STATE.with(|state| {
+        let nfts: Vec<SkillNFT> = state
+            .borrow()
+            .nfts
+            .values()
+            .filter(|nft| nft.is_active && metadata_matches(&nft.metadata, &filters))
+            .cloned()
+            .collect();
+        paged_nfts(nfts, offset, limit)
+    })
+}
+
+/// Get a histogram of the prices of NFTs for sale as `(bucket_upper_bound, count)` pairs.
+/// Buckets evenly split the range between the lowest and highest price, and bounds are inclusive.
+#[query]
+fn price_histogram(buckets: u32) -> Vec<(u64, u64)> {
+    let prices: Vec<u64> = STATE.with(|state| {
+        state
+            .borrow()
+            .nfts
+            .values()
+            .filter(|nft| is_for_sale(nft))
+            .map(effective_price)
+            .collect()
+    });
+    histogram(&prices, buckets)
+}
+
+/// Split `prices` into at most `buckets` equal-width buckets with distinct inclusive upper bounds.
+fn histogram(prices: &[u64], buckets: u32) -> Vec<(u64, u64)> {
+    let (Some(&min), Some(&max)) = (prices.iter().min(), prices.iter().max()) else {
+        return Vec::new();
+    };
+    if buckets == 0 {
+        return Vec::new();
+    }
+
+    let span = max - min + 1;
+    let buckets = (buckets.min(MAX_HISTOGRAM_BUCKETS) as u64).min(span);
+    let width = span.div_ceil(buckets);
+    // Rounding the width up can leave trailing buckets past `max`; drop them
+    let buckets = span.div_ceil(width);
+
+    let mut counts = vec![0u64; buckets as usize];
+    for &price in prices {
+        counts[((price - min) / width) as usize] += 1;
+    }
+
+    counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| {
+            let upper = min.saturating_add(width.saturating_mul(i as u64 + 1) - 1);
+            (upper.min(max), count)
+        })
+        .collect()
+}
+
+/// Get the mean time in nanoseconds between listing an NFT for resale and its sale, or 0 if none have sold.
+#[query]
+fn average_time_to_sale() -> u64 {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .total_time_listed
+            .checked_div(state.completed_resales)
+            .unwrap_or(0)
+    })
+}
+
+/// Get the number of distinct principals that have completed at least one purchase.
+#[query]
+fn unique_buyers() -> u64 {
+    STATE.with(|state| state.borrow().buyers.len() as u64)
+}
+
+/// Get a breakdown of minted NFTs by status.
+#[query]
+fn supply_info() -> SupplyInfo {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let active = state.nfts.values().filter(|nft| nft.is_active).count() as u64;
+        SupplyInfo {
+            minted: state.next_id,
+            active,
+            deactivated: state.nfts.len() as u64 - active,
         }
-        Err(err) => Err(format!("Failed to add balance: {:?}", err)),
+    })
+}
+
+/// Pick up to `count` of `candidates` with a partial Fisher-Yates shuffle driven by `seed`.
+fn select_featured(mut candidates: Vec<u64>, seed: &[u8], count: usize) -> Vec<u64> {
+    let count = count.min(candidates.len());
+    for i in 0..count {
+        let digest = Sha256::new().chain_update(seed).chain_update(i.to_le_bytes()).finalize();
+        let random = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let j = i + (random % (candidates.len() - i) as u64) as usize;
+        candidates.swap(i, j);
+    }
+    candidates.truncate(count);
+    candidates
+}
+
+/// Draw a fresh seed from `raw_rand` and choose a new featured rotation among the NFTs for sale.
+async fn refresh_featured_nfts() {
+    if FEATURED_REFRESHING.get() {
+        return;
     }
+    FEATURED_REFRESHING.set(true);
+    let result = raw_rand().await;
+    FEATURED_REFRESHING.set(false);
+
+    let seed = match result {
+        Ok((seed,)) => seed,
+        Err(err) => {
+            info!("Failed to refresh featured NFTs: {:?}", err);
+            // Retry once after a delay rather than waiting a full day; at most one retry is pending
+            if !FEATURED_RETRY_PENDING.get() {
+                FEATURED_RETRY_PENDING.set(true);
+                ic_cdk_timers::set_timer(Duration::from_nanos(FEATURED_RETRY_DELAY), || {
+                    FEATURED_RETRY_PENDING.set(false);
+                    ic_cdk::spawn(refresh_featured_nfts())
+                });
+            }
+            return;
+        }
+    };
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mut candidates: Vec<u64> = state
+            .nfts
+            .values()
+            .filter(|nft| is_for_sale(nft))
+            .map(|nft| nft.id)
+            .collect();
+        candidates.sort();
+        state.featured = FeaturedSelection {
+            nft_ids: select_featured(candidates, &seed, FEATURED_COUNT),
+            seed,
+            refreshed_at: api::time(),
+        };
+        info!("Featured NFTs refreshed: {:?}", state.featured.nft_ids);
+    });
+}
+
+/// Get up to `count` NFTs from the current featured rotation, which is refreshed daily.
+#[query]
+fn get_featured_nfts(count: u64) -> Vec<SkillNFT> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .featured
+            .nft_ids
+            .iter()
+            .filter_map(|id| state.nfts.get(id))
+            .filter(|nft| is_for_sale(nft))
+            .take(count as usize)
+            .cloned()
+            .collect()
+    })
 }
 
 /// Get all active NFTs.
@@ -315,3 +2098,493 @@ fn get_active_nfts() -> Vec<SkillNFT> {
 
 // Candid interface export
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    fn nft(id: u64, creator: Principal) -> SkillNFT {
+        SkillNFT {
+            id,
+            title: format!("Skill {}", id),
+            description: "A skill".to_string(),
+            creator,
+            price: 100,
+            unlock_duration: None,
+            metadata: HashMap::new(),
+            owner: creator,
+            resale_price: None,
+            is_active: true,
+            max_per_buyer: None,
+            royalties_earned: 0,
+            last_resale_price: None,
+            license: None,
+            listed_at: None,
+            resale_allowed: true,
+            acquired_at: None,
+        }
+    }
+
+    #[test]
+    fn baseline_state_migrates_on_upgrade() {
+        let creator = principal(1);
+        let mut legacy = LegacySkillTreeStorage { next_id: 1, ..Default::default() };
+        legacy.nfts.insert(0, LegacySkillNFT {
+            id: 0,
+            title: "Rust".to_string(),
+            description: "Ownership".to_string(),
+            creator,
+            price: 100,
+            unlock_duration: None,
+            metadata: HashMap::new(),
+            owner: creator,
+            resale_price: Some(150),
+            is_active: true,
+        });
+        legacy.balances.insert(creator, 500);
+        let bytes = candid::encode_args((legacy,)).unwrap();
+
+        assert!(candid::decode_args::<(SkillTreeStorage,)>(&bytes).is_err());
+        let (legacy,): (LegacySkillTreeStorage,) = candid::decode_args(&bytes).unwrap();
+        let state: SkillTreeStorage = legacy.into();
+        let nft = &state.nfts[&0];
+        assert_eq!(nft.resale_price, Some(150));
+        assert!(nft.resale_allowed);
+        assert_eq!(nft.royalties_earned, 0);
+        assert_eq!(state.next_id, 1);
+        assert_eq!(state.balances[&creator], 500);
+        assert!(state.admins.is_empty());
+        assert_eq!(state.initialized_at, None);
+    }
+
+    #[test]
+    fn forget_account_drops_per_principal_state() {
+        let (closing, other) = (principal(1), principal(2));
+        let mut state = SkillTreeStorage::default();
+        state.operator_approvals.insert(closing, HashSet::from([other]));
+        state.operator_approvals.insert(other, HashSet::from([closing]));
+        state.auto_withdraw_thresholds.insert(closing, 50_000);
+        state.first_seen.insert(closing, 1);
+        state.first_seen.insert(other, 1);
+        state.reservations.insert(0, Reservation { holder: closing, expires_at: 10 });
+
+        forget_account(&mut state, closing);
+
+        assert!(state.operator_approvals.is_empty());
+        assert!(state.auto_withdraw_thresholds.is_empty());
+        assert_eq!(state.first_seen.len(), 1);
+        assert!(state.reservations.is_empty());
+    }
+
+    #[test]
+    fn royalty_rounding_on_odd_prices() {
+        let prices = [1, 5, 9, 15, 19];
+        let royalties = |rounding| prices.map(|price| calculate_royalty(price, rounding));
+        assert_eq!(royalties(RoyaltyRounding::Floor), [0, 0, 0, 1, 1]);
+        assert_eq!(royalties(RoyaltyRounding::Round), [0, 1, 1, 2, 2]);
+        assert_eq!(royalties(RoyaltyRounding::Ceil), [1, 1, 1, 2, 2]);
+        assert_eq!(calculate_royalty(100, RoyaltyRounding::Ceil), 10);
+    }
+
+    #[test]
+    fn resale_owner_cannot_buy_own_listing() {
+        let (creator, owner) = (principal(1), principal(2));
+        let mut state = SkillTreeStorage::default();
+        let mut listed = nft(0, creator);
+        listed.owner = owner;
+        listed.resale_price = Some(200);
+        state.nfts.insert(0, listed.clone());
+
+        assert_eq!(
+            check_purchasable(&state, &listed, owner, 0),
+            Err("Cannot purchase your own NFT".to_string())
+        );
+        assert_eq!(check_purchasable(&state, &listed, principal(3), 0), Ok(()));
+    }
+
+    #[test]
+    fn secondary_owners_must_list_to_sell() {
+        let (creator, owner) = (principal(1), principal(2));
+        let state = SkillTreeStorage::default();
+        let mut skill = nft(0, creator);
+        assert!(is_for_sale(&skill));
+        assert_eq!(availability_at(&state, &skill, 0), Availability::Available);
+
+        skill.owner = owner;
+        assert!(!is_for_sale(&skill));
+        assert_eq!(availability_at(&state, &skill, 0), Availability::NotForSale);
+
+        skill.resale_price = Some(200);
+        assert!(is_for_sale(&skill));
+        assert_eq!(availability_at(&state, &skill, 0), Availability::Listed { price: 200 });
+
+        skill.is_active = false;
+        assert!(!is_for_sale(&skill));
+        assert_eq!(availability_at(&state, &skill, 0), Availability::Inactive);
+
+        // A creator who buys their NFT back must list it like any other owner
+        let mut state = SkillTreeStorage::default();
+        let mut listed = nft(1, creator);
+        listed.owner = owner;
+        listed.acquired_at = Some(1);
+        listed.resale_price = Some(200);
+        state.nfts.insert(1, listed);
+        state.balances.insert(creator, 200);
+        settle_sale(&mut state, 1, creator, owner, 200, 2).unwrap();
+        let bought_back = state.nfts[&1].clone();
+        assert!(!is_for_sale(&bought_back));
+        assert_eq!(availability_at(&state, &bought_back, 3), Availability::NotForSale);
+        assert_eq!(
+            check_purchasable(&state, &bought_back, principal(3), 3),
+            Err("NFT is not listed for resale".to_string())
+        );
+    }
+
+    #[test]
+    fn creator_rights_cannot_go_to_a_secondary_owner() {
+        let (creator, owner, buyer) = (principal(1), principal(2), principal(3));
+        let mut state = SkillTreeStorage::default();
+        let mut skill = nft(0, creator);
+        skill.owner = owner;
+        skill.acquired_at = Some(1);
+        skill.resale_allowed = false;
+        state.nfts.insert(0, skill);
+        state.balances.insert(buyer, 1_000);
+
+        assert_eq!(
+            transfer_creator(&mut state, 0, creator, owner, 2),
+            Err("Cannot transfer creator rights to the NFT's current owner".to_string())
+        );
+        let skill = state.nfts[&0].clone();
+        assert_eq!(skill.creator, creator);
+        assert!(check_resale_allowed(&skill).is_err());
+        assert_eq!(
+            check_purchasable(&state, &skill, buyer, 2),
+            Err("NFT is not listed for resale".to_string())
+        );
+
+        transfer_creator(&mut state, 0, creator, buyer, 3).unwrap();
+        assert_eq!(state.nfts[&0].creator, buyer);
+    }
+
+    #[test]
+    fn reservations_need_a_buyable_nft_and_are_limited() {
+        let (creator, buyer) = (principal(1), principal(2));
+        let mut state = SkillTreeStorage::default();
+        state.nfts.insert(0, nft(0, creator));
+        state.nfts.insert(1, nft(1, creator));
+
+        assert_eq!(reserve(&mut state, 0, buyer, 10, 0), Err("Insufficient balance".to_string()));
+        assert_eq!(reserve(&mut state, 0, creator, 10, 0), Err("Cannot purchase your own NFT".to_string()));
+
+        state.balances.insert(buyer, 100);
+        assert_eq!(reserve(&mut state, 0, buyer, 10, 0), Ok(()));
+        assert_eq!(reserve(&mut state, 1, buyer, 10, 5), Err("You already hold a reservation".to_string()));
+
+        // Once expired, the NFT is free for others but not for the previous holder until the cooldown passes
+        assert!(reserve(&mut state, 0, buyer, 10, 10).is_err());
+        assert_eq!(reserve(&mut state, 1, buyer, 10, 10), Ok(()));
+        state.reservations.remove(&1);
+        assert_eq!(reserve(&mut state, 0, buyer, 10, 10 + RESERVATION_COOLDOWN), Ok(()));
+    }
+
+    #[test]
+    fn resale_chain_pays_seller_and_creator() {
+        let (creator, a, b) = (principal(1), principal(2), principal(3));
+        let mut state = SkillTreeStorage::default();
+        state.nfts.insert(0, nft(0, creator));
+        state.balances.insert(a, 1_000);
+        state.balances.insert(b, 500);
+
+        // Primary sale: the creator keeps the price minus the royalty, which accrues separately
+        let listed = state.nfts[&0].clone();
+        check_purchasable(&state, &listed, a, 10).unwrap();
+        settle_sale(&mut state, 0, a, creator, effective_price(&listed), 10).unwrap();
+        assert_eq!(state.balances[&a], 900);
+        assert_eq!(state.balances[&creator], 90);
+        assert_eq!(state.creator_royalties[&creator], 10);
+
+        // A must list before B can buy
+        let owned = state.nfts[&0].clone();
+        assert_eq!(
+            check_purchasable(&state, &owned, b, 15),
+            Err("NFT is not listed for resale".to_string())
+        );
+        let nft = state.nfts.get_mut(&0).unwrap();
+        nft.resale_price = Some(200);
+        nft.listed_at = Some(20);
+
+        // Resale: A receives 90% of the resale price and the creator earns the royalty again
+        let listed = state.nfts[&0].clone();
+        check_purchasable(&state, &listed, b, 30).unwrap();
+        settle_sale(&mut state, 0, b, a, effective_price(&listed), 30).unwrap();
+        assert_eq!(state.balances[&b], 300);
+        assert_eq!(state.balances[&a], 1_080);
+        assert_eq!(state.balances[&creator], 90);
+        assert_eq!(state.creator_royalties[&creator], 30);
+
+        let nft = &state.nfts[&0];
+        assert_eq!(nft.owner, b);
+        assert_eq!(nft.resale_price, None);
+        assert_eq!(nft.last_resale_price, Some(200));
+        assert_eq!(nft.royalties_earned, 30);
+        assert_eq!(nft.acquired_at, Some(30));
+        assert_eq!((state.completed_resales, state.total_time_listed), (1, 10));
+
+        // Only the primary sale counts as the creator's; the resale by A does not
+        assert_eq!(state.creator_sales, HashMap::from([(creator, HashMap::from([(a, 1)]))]));
+    }
+
+    #[test]
+    fn access_expires_at_unlock_boundary() {
+        let (creator, owner) = (principal(1), principal(2));
+        let mut skill = nft(0, creator);
+        skill.owner = owner;
+        skill.acquired_at = Some(1_000);
+        assert!(access_valid(&skill, owner, u64::MAX));
+
+        skill.unlock_duration = Some(500);
+        assert!(access_valid(&skill, owner, 1_000 + 500 - 1));
+        assert!(!access_valid(&skill, owner, 1_000 + 500));
+        assert!(access_valid(&skill, creator, 1_000 + 500));
+        assert!(!access_valid(&skill, principal(3), 1_000));
+    }
+
+    #[test]
+    fn histogram_bounds_are_distinct() {
+        assert_eq!(histogram(&[1, 10], 6), vec![(2, 1), (4, 0), (6, 0), (8, 0), (10, 1)]);
+        assert_eq!(histogram(&[5, 5, 5], 4), vec![(5, 3)]);
+        assert_eq!(histogram(&[1, 2, 3, 4], 2), vec![(2, 2), (4, 2)]);
+        assert!(histogram(&[], 4).is_empty());
+        assert!(histogram(&[1], 0).is_empty());
+    }
+
+    #[test]
+    fn pages_cover_every_nft_once() {
+        let creator = principal(1);
+        // Insert out of order so the result cannot depend on input order
+        let nfts: Vec<SkillNFT> = (0..250).rev().map(|id| nft(id, creator)).collect();
+
+        let mut seen = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = page_by_id(nfts.clone(), offset, MAX_PAGE_SIZE);
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len() as u64;
+            seen.extend(page.into_iter().map(|nft| nft.id));
+        }
+        assert_eq!(seen, (0..250).collect::<Vec<u64>>());
+        assert_eq!(page_by_id(nfts.clone(), 0, 1_000).len(), MAX_PAGE_SIZE as usize);
+        assert!(page_by_id(nfts, 1 << 32, 10).is_empty());
+    }
+
+    #[test]
+    fn sale_overflowing_a_credit_is_rejected_unchanged() {
+        let (creator, seller, buyer) = (principal(1), principal(2), principal(3));
+        let mut state = SkillTreeStorage::default();
+        let mut listed = nft(0, creator);
+        listed.owner = seller;
+        listed.resale_price = Some(100);
+        state.nfts.insert(0, listed);
+        state.balances.insert(buyer, 100);
+
+        state.balances.insert(seller, u64::MAX - 50);
+        assert_eq!(
+            settle_sale(&mut state, 0, buyer, seller, 100, 1),
+            Err("Sale proceeds would overflow the seller's balance".to_string())
+        );
+        assert_eq!(state.balances[&buyer], 100);
+        assert_eq!(state.nfts[&0].owner, seller);
+
+        state.balances.insert(seller, 0);
+        state.creator_royalties.insert(creator, u64::MAX - 5);
+        assert_eq!(
+            settle_sale(&mut state, 0, buyer, seller, 100, 1),
+            Err("Royalty would overflow the creator's royalty balance".to_string())
+        );
+        assert_eq!(state.balances[&buyer], 100);
+        assert_eq!(state.balances[&seller], 0);
+        assert_eq!(state.nfts[&0].owner, seller);
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn deposits_are_credited_once_net_of_the_sweep_fee() {
+        let user = principal(1);
+        let fee = DEFAULT_FEE.e8s();
+        let mut state = SkillTreeStorage::default();
+
+        // First deposit: the whole account is swept and credited minus the fee
+        let amount = begin_deposit(&mut state, user, 100_000).unwrap();
+        assert_eq!(amount, 100_000);
+        assert_eq!(finish_deposit(&mut state, user, amount, true), 100_000 - fee);
+        assert!(state.last_deposit_balance.is_empty());
+
+        // Repeat call with no new funds: the swept account reads zero
+        assert_eq!(begin_deposit(&mut state, user, 0), Err("No new deposit found".to_string()));
+
+        // Second deposit, with a repeat call and a stale lower reading while its sweep is in flight
+        let amount = begin_deposit(&mut state, user, 50_000).unwrap();
+        assert_eq!(begin_deposit(&mut state, user, 50_000), Err("No new deposit found".to_string()));
+        assert_eq!(begin_deposit(&mut state, user, 20_000), Err("No new deposit found".to_string()));
+        assert_eq!(state.last_deposit_balance[&user], 50_000);
+        assert_eq!(finish_deposit(&mut state, user, amount, true), 50_000 - fee);
+        assert_eq!(state.balances[&user], 150_000 - 2 * fee);
+
+        // A failed sweep credits nothing and releases the claim for a retry
+        let amount = begin_deposit(&mut state, user, 30_000).unwrap();
+        assert_eq!(finish_deposit(&mut state, user, amount, false), 0);
+        assert_eq!(begin_deposit(&mut state, user, 30_000), Ok(30_000));
+
+        let mut fresh = SkillTreeStorage::default();
+        assert_eq!(begin_deposit(&mut fresh, user, fee), Err("Deposit does not cover the ledger fee".to_string()));
+    }
+
+    #[test]
+    fn withdrawals_debit_first_and_restore_on_failure() {
+        let user = principal(1);
+        let fee = DEFAULT_FEE.e8s();
+        let mut state = SkillTreeStorage::default();
+        state.balances.insert(user, 100_000);
+        state.creator_royalties.insert(user, 50_000);
+
+        // While the first withdrawal is in flight its amount is already gone, so a second cannot spend it
+        begin_withdrawal(&mut state, user, 60_000).unwrap();
+        assert_eq!(state.balances[&user], 40_000);
+        assert_eq!(begin_withdrawal(&mut state, user, 60_000), Err("Insufficient balance".to_string()));
+        let royalties = begin_royalty_withdrawal(&mut state, user).unwrap();
+        assert_eq!(begin_royalty_withdrawal(&mut state, user), Err("No royalties to withdraw".to_string()));
+
+        // Failed transfers give back exactly what was taken
+        restore_withdrawal(&mut state, user, 60_000);
+        restore_royalties(&mut state, user, royalties);
+        assert_eq!(state.balances[&user], 100_000);
+        assert_eq!(state.creator_royalties[&user], 50_000);
+
+        // Amounts at or below the fee are refused without being debited
+        assert_eq!(begin_withdrawal(&mut state, user, fee), Err("Amount does not cover the ledger fee".to_string()));
+        assert_eq!(state.balances[&user], 100_000);
+        state.creator_royalties.insert(user, fee);
+        assert_eq!(
+            begin_royalty_withdrawal(&mut state, user),
+            Err("Royalties do not cover the ledger fee".to_string())
+        );
+        assert_eq!(state.creator_royalties[&user], fee);
+    }
+
+    #[test]
+    fn accounts_with_nfts_for_sale_cannot_close() {
+        let (creator, owner) = (principal(1), principal(2));
+        let mut state = SkillTreeStorage::default();
+        state.nfts.insert(0, nft(0, creator));
+        assert!(check_nothing_for_sale(&state, creator).is_err());
+        state.nfts.get_mut(&0).unwrap().is_active = false;
+        assert_eq!(check_nothing_for_sale(&state, creator), Ok(()));
+
+        let mut held = nft(1, creator);
+        held.owner = owner;
+        held.acquired_at = Some(1);
+        state.nfts.insert(1, held);
+        assert_eq!(check_nothing_for_sale(&state, owner), Ok(()));
+        state.nfts.get_mut(&1).unwrap().resale_price = Some(200);
+        assert!(check_nothing_for_sale(&state, owner).is_err());
+    }
+
+    #[test]
+    fn history_records_the_lifecycle_in_order() {
+        let (creator, a, b) = (principal(1), principal(2), principal(3));
+        let mut state = SkillTreeStorage::default();
+        state.balances.insert(a, 1_000);
+
+        store_minted_nft(&mut state, nft(0, creator), 1);
+        settle_sale(&mut state, 0, a, creator, 100, 2).unwrap();
+        list_for_resale(&mut state, 0, a, 200, 3).unwrap();
+        cancel_listing(&mut state, 0, a, 4).unwrap();
+        transfer_nft(&mut state, 0, a, b, 5).unwrap();
+        deactivate(&mut state, 0, creator, false, 6).unwrap();
+        reactivate(&mut state, 0, creator, false, 7).unwrap();
+        // A rejected call leaves no trace
+        assert!(cancel_listing(&mut state, 0, b, 8).is_err());
+
+        let history: Vec<(u64, Event)> = state.history[&0]
+            .iter()
+            .map(|entry| (entry.timestamp, entry.event.clone()))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                (1, Event::Minted),
+                (2, Event::Purchased { from: creator, to: a, price: 100 }),
+                (3, Event::ResaleListed { price: 200 }),
+                (4, Event::ResaleCancelled),
+                (5, Event::Transferred { from: a, to: b }),
+                (6, Event::Deactivated),
+                (7, Event::Reactivated),
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata_filters_are_anded_with_case_insensitive_keys() {
+        let metadata: HashMap<String, String> = [("Level", "3"), ("track", "Rust")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let filter = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+
+        assert!(metadata_matches(&metadata, &[]));
+        assert!(metadata_matches(&metadata, &filter(&[("level", "3"), ("TRACK", "Rust")])));
+        assert!(!metadata_matches(&metadata, &filter(&[("level", "3"), ("track", "Go")])));
+        assert!(!metadata_matches(&metadata, &filter(&[("track", "rust")])));
+        assert!(!metadata_matches(&metadata, &filter(&[("missing", "3")])));
+    }
+
+    #[test]
+    fn admin_takedowns_need_an_admin_to_lift() {
+        let (creator, admin, stranger) = (principal(1), principal(2), principal(3));
+        let mut state = SkillTreeStorage::default();
+        store_minted_nft(&mut state, nft(0, creator), 1);
+        store_minted_nft(&mut state, nft(1, creator), 1);
+
+        assert!(deactivate(&mut state, 0, stranger, false, 2).is_err());
+
+        // Admin takedown: the creator cannot undo it, an admin can
+        deactivate(&mut state, 0, admin, true, 2).unwrap();
+        assert!(state.admin_takedowns.contains(&0));
+        assert_eq!(
+            reactivate(&mut state, 0, creator, false, 3),
+            Err("Only an admin can reactivate an NFT taken down by an admin".to_string())
+        );
+        reactivate(&mut state, 0, admin, true, 3).unwrap();
+        assert!(state.nfts[&0].is_active && state.admin_takedowns.is_empty());
+
+        // Creator deactivation: the creator may reactivate it themselves
+        deactivate(&mut state, 1, creator, false, 4).unwrap();
+        assert!(state.admin_takedowns.is_empty());
+        assert!(reactivate(&mut state, 1, stranger, false, 5).is_err());
+        reactivate(&mut state, 1, creator, false, 5).unwrap();
+    }
+
+    #[test]
+    fn admin_list_is_seeded_and_never_emptied() {
+        let (first, second) = (principal(1), principal(2));
+        let mut state = SkillTreeStorage::default();
+        seed_admins(&mut state, first);
+        seed_admins(&mut state, second);
+        assert_eq!(state.admins, vec![first]);
+
+        assert_eq!(remove_admin_from(&mut state, first), Err("Cannot remove the last admin".to_string()));
+        state.admins.push(second);
+        assert_eq!(remove_admin_from(&mut state, second), Ok(()));
+        assert_eq!(remove_admin_from(&mut state, second), Err("Principal is not an admin".to_string()));
+        assert_eq!(state.admins, vec![first]);
+    }
+}